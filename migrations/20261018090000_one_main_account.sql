@@ -0,0 +1,16 @@
+-- Only keep the oldest main account for each owner
+UPDATE accounts
+SET is_main = false
+WHERE is_main AND id NOT IN (
+    SELECT MIN(id) FROM accounts WHERE is_main GROUP BY COALESCE(user_id, discord_id)
+);
+
+-- Owners without a main account get their oldest account promoted
+UPDATE accounts
+SET is_main = true
+WHERE id IN (
+    SELECT MIN(id) FROM accounts GROUP BY COALESCE(user_id, discord_id) HAVING NOT bool_or(is_main)
+);
+
+/* owner is user_id, falling back to discord_id until user_id is enforced */
+CREATE UNIQUE INDEX accounts_one_main ON accounts ((COALESCE(user_id, discord_id))) WHERE is_main;
@@ -0,0 +1,6 @@
+create table mojang_profile_cache (
+    username VARCHAR(50) primary key, /* lowercase, mojang names are case insensitive */
+    minecraft_uuid UUID, /* null when mojang has no such profile */
+    minecraft_username VARCHAR(50),
+    expires_at timestamptz not null
+);
@@ -0,0 +1,14 @@
+create table username_history (
+    id bigserial primary key,
+    minecraft_uuid UUID not null,
+    minecraft_username VARCHAR(50) not null,
+    valid_from timestamptz not null default now(),
+    valid_to timestamptz /* null while it is the current name */
+);
+
+create index username_history_username on username_history (lower(minecraft_username));
+create unique index username_history_current on username_history (minecraft_uuid) where valid_to is null;
+
+-- We only know the current names from before history was kept.
+insert into username_history (minecraft_uuid, minecraft_username)
+select minecraft_uuid, minecraft_username from accounts;
@@ -0,0 +1,12 @@
+create table whitelist_outbox (
+    id bigserial primary key,
+    minecraft_uuid UUID not null,
+    action VARCHAR(10) not null, /* add or remove */
+    created_at timestamptz not null default now(),
+    attempts int not null default 0,
+    next_attempt_at timestamptz not null default now(),
+    last_error text,
+    delivered_at timestamptz
+);
+
+create index whitelist_outbox_pending on whitelist_outbox (next_attempt_at) where delivered_at is null;
@@ -0,0 +1,13 @@
+-- Accounts that only have a discord id have to be resolved first, see `minecraft-accounts backfill-user-ids`
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM accounts WHERE user_id IS NULL) THEN
+        RAISE EXCEPTION 'accounts without a user_id remain, run minecraft-accounts backfill-user-ids --apply first';
+    END IF;
+END $$;
+
+ALTER TABLE accounts ALTER COLUMN user_id SET NOT NULL;
+
+/* the owner is the user_id now, discord_id is only kept for the deprecated event field */
+DROP INDEX accounts_one_main;
+CREATE UNIQUE INDEX accounts_one_main ON accounts (user_id) WHERE is_main;
@@ -0,0 +1,2 @@
+-- Only runs once the service stopped reading it, see READ_FIRST_NAME
+ALTER TABLE accounts DROP COLUMN first_name;
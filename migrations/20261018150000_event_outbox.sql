@@ -0,0 +1,12 @@
+create table event_outbox (
+    id bigserial primary key,
+    minecraft_uuid UUID not null,
+    payload bytea not null, /* encoded MinecraftAccountChanged */
+    created_at timestamptz not null default now(),
+    attempts int not null default 0,
+    next_attempt_at timestamptz not null default now(),
+    last_error text,
+    published_at timestamptz
+);
+
+create index event_outbox_pending on event_outbox (next_attempt_at) where published_at is null;
@@ -0,0 +1,250 @@
+//! A tiny in-process stand-in for a nats server, enough of the client protocol for the
+//! service to run against: subjects with wildcards, queue groups, headers and no responders.
+//! Publishes to a jetstream subject are acked like a stream would, dropping repeated
+//! Nats-Msg-Ids, and kept so tests can look at what the stream stored.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+struct Sub {
+    conn: u64,
+    sid: String,
+    subject: String,
+    queue: Option<String>,
+    remaining: Option<u64>,
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct Stream {
+    name: String,
+    ids: HashSet<String>,
+    messages: Vec<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct State {
+    subs: Vec<Sub>,
+    streams: HashMap<String, Stream>,
+    next_conn: u64,
+    round_robin: usize,
+}
+
+#[derive(Clone)]
+pub struct FakeNats {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeNats {
+
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind fake nats");
+        let addr = listener.local_addr().expect("fake nats addr");
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let _state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, addr, _state.clone()));
+            }
+        });
+
+        FakeNats { addr, state }
+    }
+
+    pub fn url(&self) -> String {
+        format!("nats://{}", self.addr)
+    }
+
+    /// Stores publishes to the subject like a jetstream stream named `name` would.
+    pub fn add_stream(&self, name: &str, subject: &str) {
+        let stream = Stream { name: name.to_string(), ..Default::default() };
+        self.state.lock().unwrap().streams.insert(subject.to_string(), stream);
+    }
+
+    /// Payloads the stream on the subject stored, in order.
+    pub fn stream_messages(&self, subject: &str) -> Vec<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state.streams.get(subject).map(|s| s.messages.clone()).unwrap_or_default()
+    }
+
+    /// Waits until someone subscribed to the subject, so requests don't race the subscription.
+    pub async fn wait_for_subscriber(&self, subject: &str) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !self.state.lock().unwrap().subs.iter().any(|s| matches(&s.subject, subject)) {
+            assert!(Instant::now() < deadline, "nobody subscribed to {}", subject);
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+}
+
+/// Whether a subscription subject, which may have `*` and `>` wildcards, matches the subject.
+fn matches(pattern: &str, subject: &str) -> bool {
+    let mut subject = subject.split('.');
+    for token in pattern.split('.') {
+        match (token, subject.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {},
+            (token, Some(s)) if token == s => {},
+            _ => return false,
+        }
+    }
+    subject.next().is_none()
+}
+
+fn header<'a>(headers: &'a [u8], name: &str) -> Option<&'a str> {
+    std::str::from_utf8(headers).ok()?
+        .lines()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.trim())
+}
+
+fn frame(subject: &str, sid: &str, reply: Option<&str>, headers: Option<&[u8]>, payload: &[u8]) -> Vec<u8> {
+    let reply = reply.map(|r| format!(" {}", r)).unwrap_or_default();
+    let mut out = match headers {
+        Some(h) => format!("HMSG {} {}{} {} {}\r\n", subject, sid, reply, h.len(), h.len() + payload.len()).into_bytes(),
+        None => format!("MSG {} {}{} {}\r\n", subject, sid, reply, payload.len()).into_bytes(),
+    };
+    if let Some(h) = headers {
+        out.extend_from_slice(h);
+    }
+    out.extend_from_slice(payload);
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+fn publish(state: &Arc<Mutex<State>>, subject: &str, reply: Option<&str>, headers: Option<&[u8]>, payload: &[u8]) {
+    let mut ack = None;
+    let delivered = {
+        let mut state = state.lock().unwrap();
+
+        if let (Some(stream), Some(_)) = (state.streams.get_mut(subject), reply) {
+            let id = headers.and_then(|h| header(h, "Nats-Msg-Id")).map(|id| id.to_string());
+            let duplicate = id.as_ref().is_some_and(|id| !stream.ids.insert(id.clone()));
+            if !duplicate {
+                stream.messages.push(payload.to_vec());
+            }
+            ack = Some(format!(
+                r#"{{"stream":"{}","seq":{}{}}}"#,
+                stream.name, stream.messages.len(), if duplicate { r#","duplicate":true"# } else { "" },
+            ));
+        }
+
+        // Everyone outside a queue group gets it, and one member of each queue group.
+        let mut targets: Vec<usize> = Vec::new();
+        let mut groups: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, sub) in state.subs.iter().enumerate() {
+            if matches(&sub.subject, subject) {
+                match &sub.queue {
+                    None => targets.push(i),
+                    Some(queue) => groups.entry(queue.clone()).or_default().push(i),
+                }
+            }
+        }
+        for members in groups.values() {
+            state.round_robin += 1;
+            targets.push(members[state.round_robin % members.len()]);
+        }
+
+        for &i in &targets {
+            let sub = &mut state.subs[i];
+            let _ = sub.tx.send(frame(subject, &sub.sid, reply, headers, payload));
+            if let Some(remaining) = sub.remaining.as_mut() {
+                *remaining -= 1;
+            }
+        }
+        state.subs.retain(|s| s.remaining != Some(0));
+
+        !targets.is_empty()
+    };
+
+    if let (Some(ack), Some(reply)) = (ack, reply) {
+        publish(state, reply, None, None, ack.as_bytes());
+    } else if let (false, Some(reply)) = (delivered, reply) {
+        publish(state, reply, None, Some(b"NATS/1.0 503\r\n\r\n"), b"");
+    }
+}
+
+async fn serve(stream: TcpStream, addr: SocketAddr, state: Arc<Mutex<State>>) {
+    let conn = {
+        let mut state = state.lock().unwrap();
+        state.next_conn += 1;
+        state.next_conn
+    };
+    let (read, mut write) = stream.into_split();
+    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+
+    tokio::spawn(async move {
+        while let Some(bytes) = rx.recv().await {
+            if write.write_all(&bytes).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let info = format!(
+        r#"INFO {{"server_id":"fake","server_name":"fake","version":"2.10.0","proto":1,"host":"{}","port":{},"headers":true,"max_payload":1048576}}"#,
+        addr.ip(), addr.port(),
+    );
+    let _ = tx.send(format!("{}\r\n", info).into_bytes());
+
+    let mut reader = BufReader::new(read);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {},
+        }
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some(op) = args.first() else { continue };
+
+        match (op.to_uppercase().as_str(), &args[1..]) {
+            ("PING", _) => { let _ = tx.send(b"PONG\r\n".to_vec()); },
+            ("SUB", [subject, sid]) | ("SUB", [subject, _, sid]) => {
+                let queue = if args.len() == 4 { Some(args[2].to_string()) } else { None };
+                state.lock().unwrap().subs.push(Sub {
+                    conn,
+                    sid: sid.to_string(),
+                    subject: subject.to_string(),
+                    queue,
+                    remaining: None,
+                    tx: tx.clone(),
+                });
+            },
+            ("UNSUB", [sid, rest @ ..]) => {
+                let max = rest.first().and_then(|m| m.parse().ok());
+                let mut state = state.lock().unwrap();
+                match max {
+                    Some(max) => state.subs.iter_mut()
+                        .filter(|s| s.conn == conn && s.sid == *sid)
+                        .for_each(|s| s.remaining = Some(max)),
+                    None => state.subs.retain(|s| !(s.conn == conn && s.sid == *sid)),
+                }
+            },
+            ("PUB", [subject, rest @ ..]) | ("HPUB", [subject, rest @ ..]) => {
+                let headers_len: usize = if *op == "HPUB" { rest[rest.len() - 2].parse().unwrap_or(0) } else { 0 };
+                let total: usize = rest[rest.len() - 1].parse().unwrap_or(0);
+                let reply = if rest.len() == if *op == "HPUB" { 3 } else { 2 } { Some(rest[0].to_string()) } else { None };
+
+                let mut body = vec![0u8; total + 2];
+                if reader.read_exact(&mut body).await.is_err() {
+                    break;
+                }
+                let headers = if *op == "HPUB" { Some(&body[..headers_len]) } else { None };
+                publish(&state, subject, reply.as_deref(), headers, &body[headers_len..total]);
+            },
+            _ => {},
+        }
+    }
+
+    state.lock().unwrap().subs.retain(|s| s.conn != conn);
+}
@@ -0,0 +1,703 @@
+//! Runs the service's handler wiring against a fake nats server, the in-memory store,
+//! a fake mojang api and a fake game server, and drives it with protobuf requests.
+
+mod fake_nats;
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use async_nats::Client;
+use bytes::Bytes;
+use async_nats::service::{Service, NATS_SERVICE_ERROR, NATS_SERVICE_ERROR_CODE};
+use futures::StreamExt;
+use protobuf::Message;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use crate::events::CHANGED_SUBJECT;
+use crate::e2e::fake_nats::FakeNats;
+use crate::jobs::backfill_user_ids::backfill_user_ids;
+use crate::jobs::event_outbox::event_outbox;
+use crate::jobs::reconcile::reconcile_with_window;
+use crate::jobs::whitelist_outbox::whitelist_outbox_with_interval;
+use crate::mojang::fake::FakeMojang;
+use crate::mojang::limiter::RateLimiter;
+use crate::mojang::{HttpMojangClient, MojangClient};
+use crate::proto::minecraft_account::MinecraftAccount;
+use crate::proto::minecraft_account_add::AddMinecraftAccountRequest;
+use crate::proto::minecraft_account_error::MinecraftAccountErrorCode;
+use crate::proto::minecraft_account_get::{GetMinecraftAccountRequest, GetMinecraftAccountResponse};
+use crate::proto::minecraft_account_history::{UsernameHistoryRequest, UsernameHistoryResponse};
+use crate::proto::minecraft_account_list::{ListMinecraftAccountsRequest, ListMinecraftAccountsResponse};
+use crate::proto::minecraft_account_remove::RemoveMinecraftAccountRequest;
+use crate::proto::minecraft_account_set_main::SetMainMinecraftAccountRequest;
+use crate::proto::minecraft_account_update::{ChangeMinecraftAccountResponse, MinecraftAccountChangeType, MinecraftAccountChanged, UpdateMinecraftAccountRequest};
+use crate::proto::user_by_discord::{GetUserByDiscordIdRequest, GetUserByDiscordIdResponse};
+use crate::proto::whitelist::{ListWhitelistResponse, UnwhitelistAccount, WhitelistAccount, WhitelistResponse};
+use crate::store::AccountStore;
+use crate::store::memory::MemoryStore;
+use crate::util;
+
+const NOTCH_ID: &str = "069a79f444e94726a5befca90e38aaf5";
+const NOTCH_UUID: &str = "069a79f4-44e9-4726-a5be-fca90e38aaf5";
+const JEB_ID: &str = "853c80ef3c3749fdaa49938b674adae6";
+const JEB_UUID: &str = "853c80ef-3c37-49fd-aa49-938b674adae6";
+
+/// How the fake game server answers whitelist requests.
+#[derive(Clone)]
+enum GameServer {
+    Accept,
+    Reject(String),
+    /// Never replies, so the request times out.
+    Silent,
+}
+
+struct Harness {
+    nc: Client,
+    nats: FakeNats,
+    service: Arc<Service>,
+    store: Arc<MemoryStore>,
+    /// (subject, uuid) of every whitelist request the game server got.
+    whitelist: Arc<Mutex<Vec<(String, String)>>>,
+    /// The uuids the game server lists as whitelisted.
+    whitelisted: Arc<Mutex<Vec<String>>>,
+    game_server: Arc<Mutex<GameServer>>,
+    shutdown: watch::Sender<bool>,
+    set: JoinSet<()>,
+}
+
+impl Harness {
+
+    async fn start() -> Self {
+        let nats = FakeNats::start().await;
+        nats.add_stream("MINECRAFT_ACCOUNTS_CHANGED", CHANGED_SUBJECT);
+
+        let mojang = FakeMojang::start().await;
+        mojang.add_profile(NOTCH_ID, "Notch");
+        mojang.add_profile(JEB_ID, "jeb_");
+        // Mojang also answers for a name jeb_ could rename to.
+        mojang.add_profile(JEB_ID, "jeb");
+        let limiter = Arc::new(RateLimiter::new(100, 100.0));
+        let mojang: Arc<dyn MojangClient> = Arc::new(HttpMojangClient::new(&mojang.url(), &mojang.url(), limiter));
+
+        let nc = async_nats::connect(nats.url()).await.expect("connect to fake nats");
+        let store = Arc::new(MemoryStore::new());
+        let accounts: Arc<dyn AccountStore> = store.clone();
+        let service = Arc::new(util::start_service(&nc, "minecraft-accounts", "minecraft-accounts").await.expect("start service"));
+
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let mut set = JoinSet::new();
+        crate::spawn_handlers(&mut set, &nc, &service, &accounts, &mojang, &shutdown_rx);
+        tokio::spawn(event_outbox(accounts.clone(), nc.clone()));
+        // Checks often, so retried whitelist changes don't keep the tests waiting.
+        tokio::spawn(whitelist_outbox_with_interval(accounts.clone(), nc.clone(), Duration::from_millis(50)));
+
+        // A game server that accepts every whitelist change, until told otherwise.
+        let whitelist = Arc::new(Mutex::new(Vec::new()));
+        let whitelisted = Arc::new(Mutex::new(Vec::new()));
+        let game_server = Arc::new(Mutex::new(GameServer::Accept));
+        let mut requests = nc.subscribe("minecraft.whitelist.*").await.expect("subscribe to whitelist");
+        let _nc = nc.clone();
+        let _whitelist = whitelist.clone();
+        let _whitelisted = whitelisted.clone();
+        let _game_server = game_server.clone();
+        tokio::spawn(async move {
+            while let Some(msg) = requests.next().await {
+                if msg.subject.as_str() == "minecraft.whitelist.list" {
+                    let game_server = _game_server.lock().unwrap().clone();
+                    if let (Some(reply), GameServer::Accept) = (msg.reply, game_server) {
+                        let mut resp = ListWhitelistResponse::new();
+                        resp.server = "survival".to_string();
+                        resp.uuids = _whitelisted.lock().unwrap().clone();
+                        let _ = _nc.publish(reply, resp.write_to_bytes().unwrap().into()).await;
+                    }
+                    continue;
+                }
+                let uuid = match msg.subject.as_str() {
+                    "minecraft.whitelist.add" => WhitelistAccount::parse_from_bytes(&msg.payload).unwrap().uuid,
+                    _ => UnwhitelistAccount::parse_from_bytes(&msg.payload).unwrap().uuid,
+                };
+                _whitelist.lock().unwrap().push((msg.subject.to_string(), uuid));
+                let (reply, payload) = match (msg.reply, _game_server.lock().unwrap().clone()) {
+                    (Some(reply), GameServer::Accept) => (reply, Vec::new()),
+                    (Some(reply), GameServer::Reject(message)) => {
+                        let mut resp = WhitelistResponse::new();
+                        resp.success = false;
+                        resp.error_message = Some(message);
+                        (reply, resp.write_to_bytes().unwrap())
+                    },
+                    _ => continue,
+                };
+                let _ = _nc.publish(reply, payload.into()).await;
+            }
+        });
+
+        for subject in ["accounts.minecraft.add", "accounts.minecraft.remove", "accounts.minecraft.list", "accounts.minecraft.get", "accounts.minecraft.update", "accounts.minecraft.set_main", "accounts.minecraft.history"] {
+            nats.wait_for_subscriber(subject).await;
+        }
+
+        Harness { nc, nats, service, store, whitelist, whitelisted, game_server, shutdown, set }
+    }
+
+    /// Serves `subject` with `f` the way the service's own endpoints are served.
+    async fn serve<F, Fut>(&mut self, name: &str, subject: &str, options: util::HandlerOptions, f: F)
+    where
+        F: Fn(Client, Arc<util::Request>) -> Fut + Send + Clone + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let nc = self.nc.clone();
+        let service = self.service.clone();
+        let shutdown = self.shutdown.subscribe();
+        let (name, _subject) = (name.to_string(), subject.to_string());
+        self.set.spawn(async move {
+            util::handle_requests_with_options(nc, &service, &name, &_subject, options, shutdown, f).await.expect("test endpoint");
+        });
+        self.nats.wait_for_subscriber(subject).await;
+    }
+
+    /// Sends a request to an endpoint expected to fail, returning the service error code and status
+    /// and the reply's body.
+    async fn request_error(&self, subject: &str, headers: async_nats::HeaderMap, payload: &[u8]) -> (String, String, Bytes) {
+        let reply = self.nc.request_with_headers(subject.to_string(), headers, payload.to_vec().into()).await
+            .unwrap_or_else(|e| panic!("{} failed: {}", subject, e));
+        let headers = reply.headers.expect("service error headers");
+        (
+            headers.get(NATS_SERVICE_ERROR_CODE).expect("error code").to_string(),
+            headers.get(NATS_SERVICE_ERROR).expect("error status").to_string(),
+            reply.payload,
+        )
+    }
+
+    async fn request<Req: Message, Resp: Message>(&self, subject: &str, request: &Req) -> Resp {
+        let reply = self.nc.request(subject.to_string(), request.write_to_bytes().unwrap().into()).await
+            .unwrap_or_else(|e| panic!("{} failed: {}", subject, e));
+        Resp::parse_from_bytes(&reply.payload).unwrap()
+    }
+
+    /// The change events the stream stored, once there are `count` of them.
+    async fn changes(&self, count: usize) -> Vec<MinecraftAccountChanged> {
+        eventually(|| self.nats.stream_messages(CHANGED_SUBJECT).len() >= count).await;
+        self.nats.stream_messages(CHANGED_SUBJECT).iter()
+            .map(|m| MinecraftAccountChanged::parse_from_bytes(m).unwrap())
+            .collect()
+    }
+
+    async fn stop(mut self) {
+        self.shutdown.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async { while self.set.join_next().await.is_some() {} })
+            .await
+            .expect("handlers stop on shutdown");
+    }
+}
+
+async fn eventually(condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out waiting");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+fn add_request(user_id: &str, username: &str) -> AddMinecraftAccountRequest {
+    let mut request = AddMinecraftAccountRequest::new();
+    request.user_id = Some(user_id.to_string());
+    request.minecraft_username = username.to_string();
+    request
+}
+
+fn account(uuid: &str, username: &str) -> MinecraftAccount {
+    let mut account = MinecraftAccount::new();
+    account.minecraft_uuid = uuid.to_string();
+    account.minecraft_username = username.to_string();
+    account
+}
+
+fn set_main_request(user_id: &str, uuid: &str) -> SetMainMinecraftAccountRequest {
+    let mut request = SetMainMinecraftAccountRequest::new();
+    request.user_id = user_id.to_string();
+    request.minecraft_uuid = uuid.to_string();
+    request
+}
+
+fn remove_request(user_id: &str, uuid: &str) -> RemoveMinecraftAccountRequest {
+    let mut request = RemoveMinecraftAccountRequest::new();
+    request.user_id = user_id.to_string();
+    request.minecraft_uuid = Some(uuid.to_string());
+    request
+}
+
+#[tokio::test]
+async fn add_list_get_remove() {
+    let h = Harness::start().await;
+
+    let added: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &add_request("user-1", "Notch")).await;
+    assert!(added.success, "{:?}", added.error_message);
+    assert_eq!(added.account.minecraft_uuid, NOTCH_UUID);
+    assert!(added.account.is_main);
+
+    let mut list = ListMinecraftAccountsRequest::new();
+    list.user_id = "user-1".to_string();
+    let listed: ListMinecraftAccountsResponse = h.request("accounts.minecraft.list", &list).await;
+    assert_eq!(listed.accounts.iter().map(|a| a.minecraft_username.as_str()).collect::<Vec<_>>(), vec!["Notch"]);
+
+    let mut get = GetMinecraftAccountRequest::new();
+    get.minecraft_uuid = NOTCH_UUID.to_string();
+    let got: GetMinecraftAccountResponse = h.request("accounts.minecraft.get", &get).await;
+    assert!(got.account_found);
+
+    let removed: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.remove", &remove_request("user-1", NOTCH_UUID)).await;
+    assert!(removed.success, "{:?}", removed.error_message);
+
+    let listed: ListMinecraftAccountsResponse = h.request("accounts.minecraft.list", &list).await;
+    assert!(listed.accounts.is_empty());
+    let got: GetMinecraftAccountResponse = h.request("accounts.minecraft.get", &get).await;
+    assert!(!got.account_found);
+
+    let changes = h.changes(2).await;
+    assert_eq!(changes.iter().map(|c| c.change.enum_value().unwrap()).collect::<Vec<_>>(),
+               vec![MinecraftAccountChangeType::ADDED, MinecraftAccountChangeType::REMOVED]);
+    assert!(changes.iter().all(|c| c.account.minecraft_uuid == NOTCH_UUID));
+    assert!(changes.iter().all(|c| c.user_id.as_deref() == Some("user-1") && c.deprecated_discord_id.is_none()));
+
+    assert_eq!(*h.whitelist.lock().unwrap(), vec![
+        ("minecraft.whitelist.add".to_string(), NOTCH_UUID.to_string()),
+        ("minecraft.whitelist.remove".to_string(), NOTCH_UUID.to_string()),
+    ]);
+
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn add_stores_mojangs_spelling() {
+    let h = Harness::start().await;
+
+    let added: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &add_request("user-1", "notch")).await;
+    assert!(added.success, "{:?}", added.error_message);
+    assert_eq!(added.account.minecraft_username, "Notch");
+
+    let stored = h.store.get_by_minecraft(NOTCH_UUID).await.unwrap().unwrap();
+    assert_eq!(stored.minecraft_username, "Notch");
+
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn add_replies_with_error_codes() {
+    let h = Harness::start().await;
+
+    let unknown: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &add_request("user-1", "Nobody")).await;
+    assert!(!unknown.success);
+    assert_eq!(unknown.error_code.enum_value().unwrap(), MinecraftAccountErrorCode::MINECRAFT_USERNAME_NOT_FOUND);
+
+    let _: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &add_request("user-1", "Notch")).await;
+    let again: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &add_request("user-2", "Notch")).await;
+    assert!(!again.success);
+    assert_eq!(again.error_code.enum_value().unwrap(), MinecraftAccountErrorCode::ALREADY_REGISTERED);
+
+    let mut anonymous = add_request("user-1", "jeb_");
+    anonymous.user_id = None;
+    anonymous.deprecated_discord_id = Some("discord-1".to_string());
+    let anonymous: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &anonymous).await;
+    assert_eq!(anonymous.error_code.enum_value().unwrap(), MinecraftAccountErrorCode::INVALID_REQUEST);
+
+    assert_eq!(h.changes(1).await.len(), 1);
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn rejected_whitelist_changes_are_retried_by_the_outbox() {
+    let h = Harness::start().await;
+    *h.game_server.lock().unwrap() = GameServer::Reject("whitelist is full".to_string());
+
+    // The account is saved, the reply says it isn't whitelisted yet.
+    let added: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &add_request("user-1", "Notch")).await;
+    assert!(added.success);
+    assert_eq!(added.error_code.enum_value().unwrap(), MinecraftAccountErrorCode::WHITELIST_FAILED);
+    assert_eq!(added.error_message.as_deref(),
+               Some("Minecraft Account was added, but the game server rejected the whitelist request: whitelist is full. It will be retried."));
+    assert!(h.store.uuid_exists(NOTCH_UUID).await.unwrap());
+
+    let outbox = h.store.whitelist_outbox();
+    assert_eq!(outbox.len(), 1);
+    let (uuid, attempts, last_error, delivered) = &outbox[0];
+    assert_eq!(uuid, NOTCH_UUID);
+    assert_eq!(*attempts, 1);
+    assert_eq!(last_error.as_deref(), Some("whitelist request rejected: whitelist is full"));
+    assert!(!delivered);
+
+    // The outbox job delivers it once the backoff passed and the game server takes it.
+    *h.game_server.lock().unwrap() = GameServer::Accept;
+    eventually(|| h.store.whitelist_outbox()[0].3).await;
+    assert_eq!(*h.whitelist.lock().unwrap(), vec![
+        ("minecraft.whitelist.add".to_string(), NOTCH_UUID.to_string()),
+        ("minecraft.whitelist.add".to_string(), NOTCH_UUID.to_string()),
+    ]);
+
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn silent_game_servers_time_out_the_whitelist_request() {
+    let h = Harness::start().await;
+    *h.game_server.lock().unwrap() = GameServer::Silent;
+
+    // Replies once WHITELIST_TIMEOUT_MS (250ms by default) passed.
+    let started = Instant::now();
+    let added: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &add_request("user-1", "Notch")).await;
+    assert!(started.elapsed() >= Duration::from_millis(250));
+    assert!(added.success);
+    assert_eq!(added.error_code.enum_value().unwrap(), MinecraftAccountErrorCode::WHITELIST_TIMEOUT);
+    assert_eq!(added.error_message.as_deref(),
+               Some("Minecraft Account was added, but the whitelist request timed out. It will be retried."));
+
+    let removed: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.remove", &remove_request("user-1", NOTCH_UUID)).await;
+    assert!(removed.success);
+    assert!(!h.store.uuid_exists(NOTCH_UUID).await.unwrap());
+
+    // The add is still pending, so the remove waits behind it instead of overtaking it.
+    let outbox = h.store.whitelist_outbox();
+    assert_eq!(outbox.len(), 2);
+    assert_eq!(outbox[0].2.as_deref(), Some("whitelist request timed out"));
+    assert!(outbox.iter().all(|(_, _, _, delivered)| !delivered));
+
+    *h.game_server.lock().unwrap() = GameServer::Accept;
+    eventually(|| h.store.whitelist_outbox().iter().all(|(_, _, _, delivered)| *delivered)).await;
+    let whitelist = h.whitelist.lock().unwrap().clone();
+    assert_eq!(whitelist.last().unwrap(), &("minecraft.whitelist.remove".to_string(), NOTCH_UUID.to_string()));
+
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn remove_broadcasts_the_owner() {
+    let h = Harness::start().await;
+
+    for username in ["Notch", "jeb_"] {
+        let mut add = add_request("user-1", username);
+        add.deprecated_discord_id = Some("discord-1".to_string());
+        let added: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &add).await;
+        assert!(added.success, "{:?}", added.error_message);
+    }
+
+    let removed: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.remove", &remove_request("user-1", NOTCH_UUID)).await;
+    assert!(removed.success, "{:?}", removed.error_message);
+
+    // Removing the main account promotes the other one, both events name the owner.
+    let changes = h.changes(4).await;
+    assert_eq!(changes[2].change.enum_value().unwrap(), MinecraftAccountChangeType::REMOVED);
+    assert_eq!(changes[3].change.enum_value().unwrap(), MinecraftAccountChangeType::UPDATED);
+    assert_eq!(changes[3].account.minecraft_uuid, JEB_UUID);
+    for change in &changes[2..] {
+        assert_eq!(change.user_id.as_deref(), Some("user-1"));
+        assert_eq!(change.deprecated_discord_id.as_deref(), Some("discord-1"));
+    }
+
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn set_main_swaps_the_main_account() {
+    let h = Harness::start().await;
+    for username in ["Notch", "jeb_"] {
+        let added: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &add_request("user-1", username)).await;
+        assert!(added.success, "{:?}", added.error_message);
+    }
+
+    let stolen: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.set_main", &set_main_request("user-2", JEB_UUID)).await;
+    assert!(!stolen.success);
+    assert_eq!(stolen.error_code.enum_value().unwrap(), MinecraftAccountErrorCode::NOT_OWNER);
+    assert!(h.store.get_by_minecraft(NOTCH_UUID).await.unwrap().unwrap().is_main);
+
+    let swapped: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.set_main", &set_main_request("user-1", JEB_UUID)).await;
+    assert!(swapped.success, "{:?}", swapped.error_message);
+    assert_eq!(swapped.account.minecraft_uuid, JEB_UUID);
+    assert!(swapped.account.is_main);
+    let mains: Vec<String> = h.store.get_by_user("user-1").await.unwrap().into_iter()
+        .filter(|a| a.is_main)
+        .map(|a| a.minecraft_uuid)
+        .collect();
+    assert_eq!(mains, vec![JEB_UUID.to_string()]);
+
+    // Both accounts that were touched are announced.
+    let changes = h.changes(4).await;
+    assert_eq!(changes[2..].iter().map(|c| (c.change.enum_value().unwrap(), c.account.minecraft_uuid.as_str(), c.account.is_main)).collect::<Vec<_>>(), vec![
+        (MinecraftAccountChangeType::UPDATED, NOTCH_UUID, false),
+        (MinecraftAccountChangeType::UPDATED, JEB_UUID, true),
+    ]);
+
+    // Already the main account, so nothing changes. The remove is the next change after the swap.
+    let again: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.set_main", &set_main_request("user-1", JEB_UUID)).await;
+    assert!(again.success, "{:?}", again.error_message);
+    assert!(again.account.is_main);
+    let _: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.remove", &remove_request("user-1", NOTCH_UUID)).await;
+    let changes = h.changes(5).await;
+    assert_eq!(changes.len(), 5);
+    assert_eq!(changes[4].change.enum_value().unwrap(), MinecraftAccountChangeType::REMOVED);
+
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn reconcile_brings_the_whitelist_in_line_with_the_accounts() {
+    let h = Harness::start().await;
+    let accounts: Arc<dyn AccountStore> = h.store.clone();
+    let window = Duration::from_millis(200);
+
+    let added: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &add_request("user-1", "Notch")).await;
+    assert!(added.success, "{:?}", added.error_message);
+    eventually(|| h.store.whitelist_outbox().iter().all(|(_, _, _, delivered)| *delivered)).await;
+    h.whitelist.lock().unwrap().clear();
+
+    // The game server lost Notch and still has jeb_, who has no account.
+    *h.whitelisted.lock().unwrap() = vec![JEB_UUID.to_string()];
+
+    reconcile_with_window(accounts.clone(), h.nc.clone(), false, window).await.expect("dry run");
+    assert!(h.whitelist.lock().unwrap().is_empty(), "a dry run changes nothing");
+
+    reconcile_with_window(accounts.clone(), h.nc.clone(), true, window).await.expect("reconcile");
+    assert_eq!(*h.whitelist.lock().unwrap(), vec![
+        ("minecraft.whitelist.add".to_string(), NOTCH_UUID.to_string()),
+        ("minecraft.whitelist.remove".to_string(), JEB_UUID.to_string()),
+    ]);
+
+    // Without any game server listing its whitelist there is nothing to compare with.
+    *h.game_server.lock().unwrap() = GameServer::Silent;
+    assert!(reconcile_with_window(accounts, h.nc.clone(), true, window).await.is_err());
+
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn backfill_gives_legacy_accounts_to_their_user() {
+    let h = Harness::start().await;
+    h.store.add_legacy_account("discord-1", &account(NOTCH_UUID, "Notch"));
+    h.store.add_legacy_account("discord-2", &account(JEB_UUID, "jeb_"));
+
+    // A users service that only knows discord-1.
+    let mut requests = h.nc.subscribe("users.get_by_discord").await.unwrap();
+    let nc = h.nc.clone();
+    tokio::spawn(async move {
+        while let Some(msg) = requests.next().await {
+            let mut resp = GetUserByDiscordIdResponse::new();
+            if GetUserByDiscordIdRequest::parse_from_bytes(&msg.payload).unwrap().discord_id == "discord-1" {
+                resp.user_id = Some("user-1".to_string());
+            }
+            let _ = nc.publish(msg.reply.unwrap(), resp.write_to_bytes().unwrap().into()).await;
+        }
+    });
+    h.nats.wait_for_subscriber("users.get_by_discord").await;
+
+    let accounts: Arc<dyn AccountStore> = h.store.clone();
+    assert!(backfill_user_ids(accounts.clone(), h.nc.clone(), true, false).await.is_err(), "discord-2 has no user");
+
+    assert_eq!(accounts.uuid_owner(NOTCH_UUID).await.unwrap().unwrap().user_id, "user-1");
+    assert_eq!(accounts.discord_only_owners().await.unwrap(), vec!["discord-2".to_string()]);
+
+    let changes = h.changes(1).await;
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].change.enum_value().unwrap(), MinecraftAccountChangeType::UPDATED);
+    assert_eq!(changes[0].account.minecraft_uuid, NOTCH_UUID);
+    assert_eq!(changes[0].user_id.as_deref(), Some("user-1"));
+    assert_eq!(changes[0].deprecated_discord_id.as_deref(), Some("discord-1"));
+
+    backfill_user_ids(accounts.clone(), h.nc.clone(), true, true).await.expect("deletes the accounts of discord-2");
+    assert!(!accounts.uuid_exists(JEB_UUID).await.unwrap());
+    assert!(accounts.accounts_without_user_id().await.unwrap().is_empty());
+
+    let changes = h.changes(2).await;
+    assert_eq!(changes[1].change.enum_value().unwrap(), MinecraftAccountChangeType::REMOVED);
+    assert_eq!(changes[1].account.minecraft_uuid, JEB_UUID);
+    assert_eq!(changes[1].deprecated_discord_id.as_deref(), Some("discord-2"));
+
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn update_only_takes_names_mojang_has_on_the_account() {
+    let h = Harness::start().await;
+    let _: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &add_request("user-1", "Notch")).await;
+
+    let mut update = UpdateMinecraftAccountRequest::new();
+    update.user_id = "user-1".to_string();
+    update.minecraft_uuid = NOTCH_UUID.to_string();
+    update.minecraft_username = Some("jeb_".to_string());
+    let stolen: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.update", &update).await;
+    assert!(!stolen.success);
+    assert_eq!(stolen.error_code.enum_value().unwrap(), MinecraftAccountErrorCode::MINECRAFT_USERNAME_MISMATCH);
+
+    update.minecraft_username = Some("notch".to_string());
+    let renamed: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.update", &update).await;
+    assert!(renamed.success, "{:?}", renamed.error_message);
+    assert_eq!(renamed.account.minecraft_username, "Notch");
+
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn update_without_changes_broadcasts_nothing() {
+    let h = Harness::start().await;
+    let _: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &add_request("user-1", "Notch")).await;
+
+    let mut update = UpdateMinecraftAccountRequest::new();
+    update.user_id = "user-1".to_string();
+    update.minecraft_uuid = NOTCH_UUID.to_string();
+    let unchanged: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.update", &update).await;
+    assert!(unchanged.success, "{:?}", unchanged.error_message);
+
+    update.minecraft_username = Some("Notch".to_string());
+    update.is_main = Some(true);
+    let unchanged: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.update", &update).await;
+    assert!(unchanged.success, "{:?}", unchanged.error_message);
+    assert_eq!(unchanged.account.minecraft_username, "Notch");
+
+    // The remove is the next change after the add.
+    let _: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.remove", &remove_request("user-1", NOTCH_UUID)).await;
+    let changes = h.changes(2).await;
+    assert_eq!(changes.iter().map(|c| c.change.enum_value().unwrap()).collect::<Vec<_>>(),
+               vec![MinecraftAccountChangeType::ADDED, MinecraftAccountChangeType::REMOVED]);
+
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn history_follows_renames_and_removes() {
+    let h = Harness::start().await;
+    let _: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &add_request("user-1", "jeb_")).await;
+
+    let mut update = UpdateMinecraftAccountRequest::new();
+    update.user_id = "user-1".to_string();
+    update.minecraft_uuid = JEB_UUID.to_string();
+    update.minecraft_username = Some("jeb".to_string());
+    let renamed: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.update", &update).await;
+    assert!(renamed.success, "{:?}", renamed.error_message);
+
+    let history = |username: &str| {
+        let mut request = UsernameHistoryRequest::new();
+        request.minecraft_username = username.to_string();
+        request
+    };
+
+    // The old name ended with the rename, and carries the account as it is now.
+    let old: UsernameHistoryResponse = h.request("accounts.minecraft.history", &history("jeb_")).await;
+    assert_eq!(old.entries.len(), 1);
+    assert_eq!(old.entries[0].minecraft_uuid, JEB_UUID);
+    assert_eq!(old.entries[0].minecraft_username, "jeb_");
+    assert!(old.entries[0].valid_to.is_some());
+    assert_eq!(old.entries[0].account.minecraft_username, "jeb");
+
+    // Names are looked up without regard to case.
+    let current: UsernameHistoryResponse = h.request("accounts.minecraft.history", &history("JEB")).await;
+    assert_eq!(current.entries.len(), 1);
+    assert_eq!(current.entries[0].minecraft_username, "jeb");
+    assert!(current.entries[0].valid_to.is_none());
+
+    // A removed account's name ends with it, and the entry has no account left.
+    let _: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.remove", &remove_request("user-1", JEB_UUID)).await;
+    let removed: UsernameHistoryResponse = h.request("accounts.minecraft.history", &history("jeb")).await;
+    assert_eq!(removed.entries.len(), 1);
+    assert!(removed.entries[0].valid_to.is_some());
+    assert!(removed.entries[0].account.is_none());
+
+    let unknown: UsernameHistoryResponse = h.request("accounts.minecraft.history", &history("Nobody")).await;
+    assert!(unknown.entries.is_empty());
+
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn failed_rename_leaves_the_main_account() {
+    let h = Harness::start().await;
+    let _: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &add_request("user-1", "Notch")).await;
+    let _: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.add", &add_request("user-1", "jeb_")).await;
+
+    // Another stored account still has the name, so the rename breaks the unique username.
+    h.store.add_account("user-2", None, &account("61699b2e-d327-4a01-9f1e-0ea8c3f06bc6", "jeb")).await.unwrap();
+
+    let mut update = UpdateMinecraftAccountRequest::new();
+    update.user_id = "user-1".to_string();
+    update.minecraft_uuid = JEB_UUID.to_string();
+    update.minecraft_username = Some("jeb".to_string());
+    update.is_main = Some(true);
+    let updated: ChangeMinecraftAccountResponse = h.request("accounts.minecraft.update", &update).await;
+    assert!(!updated.success);
+
+    let mut get = GetMinecraftAccountRequest::new();
+    get.minecraft_uuid = NOTCH_UUID.to_string();
+    let notch: GetMinecraftAccountResponse = h.request("accounts.minecraft.get", &get).await;
+    assert!(notch.account.is_main);
+
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn slow_handlers_reply_with_a_timeout() {
+    let mut h = Harness::start().await;
+    let options = util::HandlerOptions { deadline: Duration::from_millis(50), max_in_flight: 32, error_body: util::ErrorBody::Change };
+    h.serve("slow", "test.slow", options, |_nc, _msg| async {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        Ok(())
+    }).await;
+
+    let started = Instant::now();
+    let (code, status, body) = h.request_error("test.slow", async_nats::HeaderMap::new(), b"").await;
+    assert_eq!(code, "408");
+    assert!(status.starts_with("Request timed out"), "{}", status);
+    let resp = ChangeMinecraftAccountResponse::parse_from_bytes(&body).unwrap();
+    assert!(!resp.success);
+    assert_eq!(resp.error_code.enum_value().unwrap(), MinecraftAccountErrorCode::TIMED_OUT);
+    assert_eq!(resp.error_message.as_deref(), Some(status.as_str()));
+    assert!(started.elapsed() < Duration::from_secs(1), "replied at the deadline, not when the handler finished");
+
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn saturated_subjects_reply_busy() {
+    let mut h = Harness::start().await;
+    let options = util::HandlerOptions { deadline: Duration::from_secs(5), max_in_flight: 1, error_body: util::ErrorBody::Empty };
+    let (started, mut started_rx) = tokio::sync::mpsc::unbounded_channel();
+    let release = Arc::new(tokio::sync::Notify::new());
+    let _release = release.clone();
+    h.serve("busy", "test.busy", options, move |_nc, msg| {
+        let (started, release) = (started.clone(), _release.clone());
+        async move {
+            let _ = started.send(());
+            release.notified().await;
+            msg.respond("done".into()).await
+        }
+    }).await;
+
+    let nc = h.nc.clone();
+    let first = tokio::spawn(async move { nc.request("test.busy", "".into()).await });
+    started_rx.recv().await.unwrap();
+
+    let (code, status, body) = h.request_error("test.busy", async_nats::HeaderMap::new(), b"").await;
+    assert_eq!(code, "503");
+    assert!(status.starts_with("Service busy"), "{}", status);
+    assert!(body.is_empty());
+
+    release.notify_one();
+    assert_eq!(&first.await.unwrap().unwrap().payload[..], b"done");
+
+    h.stop().await;
+}
+
+#[tokio::test]
+async fn undecodable_requests_are_answered_with_the_callers_correlation_id() {
+    let h = Harness::start().await;
+
+    let mut headers = async_nats::HeaderMap::new();
+    headers.insert(util::CORRELATION_ID_HEADER, "caller-1234");
+    let (code, status, body) = h.request_error("accounts.minecraft.remove", headers, b"\xff\xff\xff").await;
+    assert_eq!(code, "400");
+    assert!(status.starts_with("Invalid request: could not decode the request"), "{}", status);
+    assert!(status.contains("(correlation id caller-1234)"), "{}", status);
+
+    // Clients that only read the body get the code and message too.
+    let resp = ChangeMinecraftAccountResponse::parse_from_bytes(&body).unwrap();
+    assert!(!resp.success);
+    assert_eq!(resp.error_code.enum_value().unwrap(), MinecraftAccountErrorCode::INVALID_REQUEST);
+    assert_eq!(resp.error_message.as_deref(), Some(status.as_str()));
+
+    h.stop().await;
+}
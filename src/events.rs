@@ -0,0 +1,80 @@
+use std::env;
+use std::time::Duration;
+use anyhow::Result;
+use async_nats::Client;
+use async_nats::jetstream::{self, stream};
+use protobuf::MessageField;
+use rand::Rng;
+use crate::proto::minecraft_account::MinecraftAccount;
+use crate::proto::minecraft_account_update::{MinecraftAccountChangeType, MinecraftAccountChanged};
+
+pub const CHANGED_SUBJECT: &str = "accounts.minecraft.changed";
+
+const DEFAULT_STREAM: &str = "MINECRAFT_ACCOUNTS_CHANGED";
+const DEFAULT_MAX_AGE_SECS: u64 = 30 * 24 * 60 * 60;
+const PUBLISH_ATTEMPTS: usize = 3;
+const BASE_BACKOFF_MS: u64 = 50;
+
+/// Creates the change event stream, or applies the configured retention to it.
+/// Uses ACCOUNT_EVENTS_STREAM, ACCOUNT_EVENTS_MAX_AGE_SECS, ACCOUNT_EVENTS_MAX_BYTES,
+/// ACCOUNT_EVENTS_MAX_MESSAGES and ACCOUNT_EVENTS_REPLICAS, unset limits are unlimited.
+pub async fn create_stream(nc: &Client) -> Result<()> {
+    let var = |name: &str| env::var(name).ok().and_then(|v| v.parse::<i64>().ok());
+
+    let config = stream::Config {
+        name: env::var("ACCOUNT_EVENTS_STREAM").unwrap_or(DEFAULT_STREAM.to_string()),
+        subjects: vec![CHANGED_SUBJECT.to_string()],
+        storage: stream::StorageType::File,
+        max_age: Duration::from_secs(var("ACCOUNT_EVENTS_MAX_AGE_SECS").map_or(DEFAULT_MAX_AGE_SECS, |v| v as u64)),
+        max_bytes: var("ACCOUNT_EVENTS_MAX_BYTES").unwrap_or(-1),
+        max_messages: var("ACCOUNT_EVENTS_MAX_MESSAGES").unwrap_or(-1),
+        num_replicas: var("ACCOUNT_EVENTS_REPLICAS").map_or(1, |v| v as usize),
+        ..Default::default()
+    };
+
+    let js = jetstream::new(nc.clone());
+    js.create_or_update_stream(config).await?;
+    Ok(())
+}
+
+/// The event for a change to the account, named by its owner's user id and legacy discord id.
+pub fn change(user_id: Option<String>, discord_id: Option<String>, change: MinecraftAccountChangeType, account: MinecraftAccount) -> MinecraftAccountChanged {
+    let mut event = MinecraftAccountChanged::new();
+    event.user_id = user_id;
+    event.deprecated_discord_id = discord_id;
+    event.change = change.into();
+    event.account = MessageField::some(account);
+    event
+}
+
+/// Publishes a change recorded in the event outbox and waits for the stream to store it. The
+/// Nats-Msg-Id is the outbox id, so the stream drops the copy if an earlier attempt, or another
+/// replica, stored it after all.
+/// Waits a jittered backoff between attempts, so a stream that is electing a leader has time to recover.
+pub async fn publish_change(nc: &Client, outbox_id: i64, payload: &[u8]) -> Result<()> {
+    let js = jetstream::new(nc.clone());
+    let msg_id = format!("minecraft-account-change-{}", outbox_id);
+
+    let mut attempt = 1;
+    loop {
+        let mut headers = async_nats::HeaderMap::new();
+        headers.insert("Nats-Msg-Id", msg_id.as_str());
+
+        let result = match js.publish_with_headers(CHANGED_SUBJECT, headers, payload.to_vec().into()).await {
+            Ok(ack) => ack.await.map(|_| ()).map_err(anyhow::Error::from),
+            Err(e) => Err(anyhow::Error::from(e)),
+        };
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < PUBLISH_ATTEMPTS => {
+                let backoff = BASE_BACKOFF_MS << attempt;
+                let backoff = Duration::from_millis(rand::thread_rng().gen_range(0..=backoff));
+                tracing::warn!("Error publishing account change, attempt {}, retrying in {:?}: {:?}", attempt, backoff, e);
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
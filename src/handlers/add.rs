@@ -1,48 +1,59 @@
 use protobuf::{Message, MessageField};
 use async_nats::Client;
-use serde::Deserialize;
-use crate::handlers::util::send_change_error;
+use std::sync::Arc;
+use crate::handlers::error::AccountError;
+use crate::handlers::util::{lookup_deadline, send_change_error, whitelist_code, whitelist_warning};
+use crate::mojang::{Lookup, MojangClient};
 use crate::proto::minecraft_account::MinecraftAccount;
 use crate::proto::minecraft_account_add::AddMinecraftAccountRequest;
-use crate::proto::minecraft_account_update::{ChangeMinecraftAccountResponse, MinecraftAccountChangeType, MinecraftAccountChanged};
-use crate::proto::whitelist::WhitelistAccount;
-use crate::store::Store;
+use crate::proto::minecraft_account_update::ChangeMinecraftAccountResponse;
+use crate::store::{AccountStore, DEPRECATED_FIRST_NAME};
+use crate::util::Request;
+use crate::whitelist;
 
 #[tracing::instrument]
-pub async fn add(db: Store, nc: Client, msg: async_nats::Message) -> anyhow::Result<()> {
+pub async fn add(db: Arc<dyn AccountStore>, mojang: Arc<dyn MojangClient>, nc: Client, msg: Arc<Request>) -> anyhow::Result<()> {
     let mut request = AddMinecraftAccountRequest::parse_from_bytes(&msg.payload)?;
 
-    if let Some(reply) = msg.reply {
+    if msg.reply.is_some() {
+
+        // Accounts belong to a user, the discord id is only kept for the deprecated event field.
+        let user_id = match request.user_id.clone() {
+            Some(user_id) => user_id,
+            None => {
+                send_change_error(&msg, AccountError::InvalidRequest("user_id is required".to_string())).await?;
+                return Ok(());
+            }
+        };
 
         // Lookup UUID
         if request.minecraft_uuid.is_none() {
-            let url = format!("https://api.mojang.com/users/profiles/minecraft/{}", request.minecraft_username);
-            let response = reqwest::get(&url).await?;
-
-            if response.status() == reqwest::StatusCode::OK  {
-                #[derive(Deserialize)]
-                struct T {
-                    pub id: String,
-                    pub name: String,
-                }
-                let response = response.json::<T>().await?;
-                request.minecraft_uuid = Some(response.id);
-            } else if response.status() == reqwest::StatusCode::NOT_FOUND {
-                send_change_error(nc.clone(), reply, "Minecraft Account was not found").await?;
-                return Ok(());
-            } else if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
-                send_change_error(nc.clone(), reply, "Minecraft Account Lookup is overload, please try again in a minute").await?;
-                return Ok(());
-            } else {
-                send_change_error(nc.clone(), reply, "Unknown error when looking up username").await?;
-                return Ok(());
+            match mojang.lookup_username(&request.minecraft_username, lookup_deadline(&msg)).await {
+                Ok(Lookup::Found(profile)) => {
+                    // Store mojang's spelling, so the username sync doesn't see it as a rename.
+                    request.minecraft_uuid = Some(profile.id);
+                    request.minecraft_username = profile.name;
+                },
+                Ok(Lookup::NotFound) => {
+                    send_change_error(&msg, AccountError::UsernameNotFound).await?;
+                    return Ok(());
+                },
+                Ok(Lookup::RateLimited) => {
+                    send_change_error(&msg, AccountError::MojangRateLimited).await?;
+                    return Ok(());
+                },
+                Err(e) => {
+                    tracing::error!("Error looking up username: {:?}", e);
+                    send_change_error(&msg, AccountError::MojangUnavailable).await?;
+                    return Ok(());
+                },
             }
         }
 
 
         // Check that the minecraft name is not already in use
         if db.uuid_exists(&request.minecraft_uuid.clone().unwrap()).await? {
-            send_change_error(nc.clone(), reply, "Minecraft Account is already registered.").await?;
+            send_change_error(&msg, AccountError::AlreadyRegistered).await?;
             return Ok(());
         }
 
@@ -50,50 +61,33 @@ pub async fn add(db: Store, nc: Client, msg: async_nats::Message) -> anyhow::Res
         let mut account = MinecraftAccount::new();
         account.minecraft_username = request.minecraft_username.clone();
         account.minecraft_uuid = request.minecraft_uuid.clone().unwrap();
-        account.deprecated_first_name = request.first_name.clone();
+        // first_name isn't stored anymore, replies carry a constant until clients stop reading it.
+        account.deprecated_first_name = DEPRECATED_FIRST_NAME.to_string();
+        // is_main is decided by the store, the first account an owner adds becomes their main.
 
-        // Is their first account (and thus main account)
-        {
-            let accounts = db.get(request.user_id.clone(), request.deprecated_discord_id.clone()).await?;
-            account.is_main = accounts.len() <= 0
-        }
-
-        // Try to whitelist the account - should probably be a different method of doing this.
-        {
-            let mut req = WhitelistAccount::new();
-            req.uuid = account.minecraft_uuid.clone();
-            let encoded: Vec<u8> = req.write_to_bytes()?;
-            nc.request("minecraft.whitelist.add", encoded.into()).await?;
-            // todo - better error handling.
-            // apparently old me said this can not actually fail... as long as we get a response.
-            // todo - some type of timeout?
-        }
-
-        // save account
-        let account = match db.add_account(request.user_id.clone(), request.deprecated_discord_id.clone(), &account).await {
-            Ok(account) => account,
+        // save account, the store queues whitelisting it
+        let added = match db.add_account(&user_id, request.deprecated_discord_id.clone(), &account).await {
+            Ok(added) => added,
             Err(e) => {
                 tracing::error!("Error creating account: {:?}", e);
-                send_change_error(nc.clone(), reply, "Internal Error creating account.").await?;
+                send_change_error(&msg, AccountError::Internal("creating account")).await?;
                 return Ok(());
             }
         };
 
+        let account = added.account;
+
+        // Whitelist it
+        let delivery = whitelist::deliver_now(&db, &nc, added.outbox_id).await;
+
         // Build and Send Response
         let mut resp = ChangeMinecraftAccountResponse::new();
         resp.success = true;
+        resp.error_code = whitelist_code(&delivery).into();
+        resp.error_message = whitelist_warning(&delivery, "Minecraft Account was added");
         resp.account = MessageField::from(Some(account.clone()));
         let encoded: Vec<u8> = resp.write_to_bytes()?;
-        nc.publish(reply, encoded.into()).await?;
-
-        // Let's broadcast the account was created.
-        let mut broadcast = MinecraftAccountChanged::new();
-        broadcast.user_id = request.user_id;
-        broadcast.deprecated_discord_id = request.deprecated_discord_id;
-        broadcast.change = MinecraftAccountChangeType::ADDED.into();
-        broadcast.account = MessageField::some(account);
-        let encoded: Vec<u8> = broadcast.write_to_bytes()?;
-        nc.publish("accounts.minecraft.changed", encoded.into()).await?;
+        msg.respond(encoded.into()).await?;
     }
 
     Ok(())
@@ -0,0 +1,95 @@
+use std::fmt;
+use sqlx::types::uuid;
+use crate::proto::minecraft_account_error::MinecraftAccountErrorCode;
+
+/// Errors a handler replies with, each has a stable code clients can match on
+/// and a message for people.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccountError {
+    NotFound,
+    NotOwner,
+    AlreadyRegistered,
+    UsernameNotFound,
+    /// Mojang has the username on a different account.
+    UsernameMismatch,
+    MojangRateLimited,
+    MojangUnavailable,
+    MainAccountRequired,
+    InvalidRequest(String),
+    /// The handler ran past its deadline.
+    TimedOut,
+    /// The subject already has its max requests in flight.
+    Busy,
+    /// What was being done, e.g. "creating account".
+    Internal(&'static str),
+}
+
+impl AccountError {
+
+    pub fn code(&self) -> MinecraftAccountErrorCode {
+        match self {
+            AccountError::NotFound => MinecraftAccountErrorCode::NOT_FOUND,
+            AccountError::NotOwner => MinecraftAccountErrorCode::NOT_OWNER,
+            AccountError::AlreadyRegistered => MinecraftAccountErrorCode::ALREADY_REGISTERED,
+            AccountError::UsernameNotFound => MinecraftAccountErrorCode::MINECRAFT_USERNAME_NOT_FOUND,
+            AccountError::UsernameMismatch => MinecraftAccountErrorCode::MINECRAFT_USERNAME_MISMATCH,
+            AccountError::MojangRateLimited => MinecraftAccountErrorCode::MOJANG_RATE_LIMITED,
+            AccountError::MojangUnavailable => MinecraftAccountErrorCode::MOJANG_UNAVAILABLE,
+            AccountError::MainAccountRequired => MinecraftAccountErrorCode::MAIN_ACCOUNT_REQUIRED,
+            AccountError::InvalidRequest(_) => MinecraftAccountErrorCode::INVALID_REQUEST,
+            AccountError::TimedOut => MinecraftAccountErrorCode::TIMED_OUT,
+            AccountError::Busy => MinecraftAccountErrorCode::SERVICE_BUSY,
+            AccountError::Internal(_) => MinecraftAccountErrorCode::INTERNAL,
+        }
+    }
+
+    /// Status code for the nats service error headers, 4xx for the caller's mistakes and
+    /// 5xx when the service or mojang is failing.
+    pub fn status(&self) -> usize {
+        match self {
+            AccountError::InvalidRequest(_) => 400,
+            AccountError::NotOwner => 403,
+            AccountError::TimedOut => 408,
+            AccountError::NotFound | AccountError::UsernameNotFound => 404,
+            AccountError::AlreadyRegistered | AccountError::MainAccountRequired | AccountError::UsernameMismatch => 409,
+            AccountError::MojangRateLimited => 429,
+            AccountError::Internal(_) => 500,
+            AccountError::MojangUnavailable | AccountError::Busy => 503,
+        }
+    }
+
+    /// Sorts an error a handler returned into bad input or an internal failure.
+    pub fn from_handler_error(e: &anyhow::Error) -> AccountError {
+        if let Some(error) = e.downcast_ref::<AccountError>() {
+            return error.clone();
+        }
+        if e.downcast_ref::<protobuf::Error>().is_some() {
+            return AccountError::InvalidRequest("could not decode the request".to_string());
+        }
+        if e.downcast_ref::<uuid::Error>().is_some() {
+            return AccountError::InvalidRequest("minecraft_uuid is not a valid uuid".to_string());
+        }
+        AccountError::Internal("handling request")
+    }
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The messages clients showed before there were codes.
+        match self {
+            AccountError::NotFound | AccountError::NotOwner => write!(f, "Unknown minecraft account."),
+            AccountError::AlreadyRegistered => write!(f, "Minecraft Account is already registered."),
+            AccountError::UsernameNotFound => write!(f, "Minecraft Account was not found"),
+            AccountError::UsernameMismatch => write!(f, "That username belongs to a different Minecraft Account."),
+            AccountError::MojangRateLimited => write!(f, "Minecraft Account Lookup is overload, please try again in a minute"),
+            AccountError::MojangUnavailable => write!(f, "Unknown error when looking up username"),
+            AccountError::MainAccountRequired => write!(f, "The main account can not be unset, set another account as main instead."),
+            AccountError::InvalidRequest(reason) => write!(f, "Invalid request: {}", reason),
+            AccountError::TimedOut => write!(f, "Request timed out"),
+            AccountError::Busy => write!(f, "Service busy"),
+            AccountError::Internal(action) => write!(f, "Internal Error {}.", action),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
@@ -1,13 +1,15 @@
 use protobuf::{Message, MessageField};
 use async_nats::Client;
+use std::sync::Arc;
 use crate::proto::minecraft_account_get::{GetMinecraftAccountRequest, GetMinecraftAccountResponse};
-use crate::store::Store;
+use crate::store::AccountStore;
+use crate::util::Request;
 
 #[tracing::instrument]
-pub async fn get(db: Store, nc: Client, msg: async_nats::Message) -> anyhow::Result<()> {
+pub async fn get(db: Arc<dyn AccountStore>, nc: Client, msg: Arc<Request>) -> anyhow::Result<()> {
     let request = GetMinecraftAccountRequest::parse_from_bytes(&msg.payload)?;
 
-    if let Some(reply) = msg.reply {
+    if msg.reply.is_some() {
 
 
         let account = db.get_by_minecraft(&request.minecraft_uuid).await?;
@@ -26,7 +28,7 @@ pub async fn get(db: Store, nc: Client, msg: async_nats::Message) -> anyhow::Res
         }
 
         let encoded: Vec<u8> = resp.write_to_bytes()?;
-        nc.publish(reply, encoded.into()).await?;
+        msg.respond(encoded.into()).await?;
     }
 
     Ok(())
@@ -0,0 +1,32 @@
+use protobuf::{Message, MessageField};
+use async_nats::Client;
+use std::sync::Arc;
+use crate::proto::minecraft_account_history::{UsernameHistoryEntry, UsernameHistoryRequest, UsernameHistoryResponse};
+use crate::store::AccountStore;
+use crate::util::Request;
+
+#[tracing::instrument]
+pub async fn history(db: Arc<dyn AccountStore>, nc: Client, msg: Arc<Request>) -> anyhow::Result<()> {
+    let request = UsernameHistoryRequest::parse_from_bytes(&msg.payload)?;
+
+    if msg.reply.is_some() {
+
+        let entries = db.username_history(&request.minecraft_username).await?;
+
+        // Build and Send Response
+        let mut resp = UsernameHistoryResponse::new();
+        for entry in entries {
+            let mut e = UsernameHistoryEntry::new();
+            e.account = MessageField::from(db.get_by_minecraft(&entry.minecraft_uuid).await?);
+            e.minecraft_uuid = entry.minecraft_uuid;
+            e.minecraft_username = entry.minecraft_username;
+            e.valid_from = entry.valid_from.timestamp();
+            e.valid_to = entry.valid_to.map(|t| t.timestamp());
+            resp.entries.push(e);
+        }
+        let encoded: Vec<u8> = resp.write_to_bytes()?;
+        msg.respond(encoded.into()).await?;
+    }
+
+    Ok(())
+}
@@ -1,22 +1,24 @@
 use protobuf::Message;
 use async_nats::Client;
+use std::sync::Arc;
 use crate::proto::minecraft_account_list::{ListMinecraftAccountsRequest, ListMinecraftAccountsResponse};
-use crate::store::Store;
+use crate::store::AccountStore;
+use crate::util::Request;
 
 #[tracing::instrument]
-pub async fn list(db: Store, nc: Client, msg: async_nats::Message) -> anyhow::Result<()> {
+pub async fn list(db: Arc<dyn AccountStore>, nc: Client, msg: Arc<Request>) -> anyhow::Result<()> {
     let request = ListMinecraftAccountsRequest::parse_from_bytes(&msg.payload)?;
 
-    if let Some(reply) = msg.reply {
+    if msg.reply.is_some() {
 
-        let accounts = db.get(Some(request.user_id.clone()), Some(request.user_id.clone())).await?;
+        let accounts = db.get_by_user(&request.user_id).await?;
 
         // Build and Send Response
         let mut resp = ListMinecraftAccountsResponse::new();
         resp.user_id = request.user_id.clone();
         resp.accounts = accounts;
         let encoded: Vec<u8> = resp.write_to_bytes()?;
-        nc.publish(reply, encoded.into()).await?;
+        msg.respond(encoded.into()).await?;
 
     }
 
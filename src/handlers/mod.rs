@@ -1,5 +1,9 @@
 pub mod add;
 pub mod remove;
 pub mod list;
-mod util;
-pub mod get;
\ No newline at end of file
+pub mod util;
+pub mod error;
+pub mod get;
+pub mod update;
+pub mod set_main;
+pub mod history;
\ No newline at end of file
@@ -1,90 +1,79 @@
-use protobuf::{Message, MessageField};
+use protobuf::Message;
 use async_nats::Client;
-use crate::handlers::util::send_change_error;
+use std::sync::Arc;
+use crate::handlers::error::AccountError;
+use crate::handlers::util::{send_change_error, whitelist_code, whitelist_warning};
 use crate::proto::minecraft_account_remove::RemoveMinecraftAccountRequest;
-use crate::proto::minecraft_account_update::{ChangeMinecraftAccountResponse, MinecraftAccountChangeType, MinecraftAccountChanged};
-use crate::proto::whitelist::UnwhitelistAccount;
-use crate::store::Store;
+use crate::proto::minecraft_account_update::ChangeMinecraftAccountResponse;
+use crate::store::AccountStore;
+use crate::util::Request;
+use crate::whitelist::{self, Delivery};
 
 #[tracing::instrument]
-pub async fn remove(db: Store, nc: Client, msg: async_nats::Message) -> anyhow::Result<()> {
+pub async fn remove(db: Arc<dyn AccountStore>, nc: Client, msg: Arc<Request>) -> anyhow::Result<()> {
     let request = RemoveMinecraftAccountRequest::parse_from_bytes(&msg.payload)?;
 
-    if let Some(reply) = msg.reply {
+    if msg.reply.is_some() {
 
         // Verify Ownership
-        let (uuid, user_id, discord_id) = {
-            let uuid;
-            if request.minecraft_uuid.is_some() {
-                uuid = request.minecraft_uuid.unwrap();
-            } else if request.deprecated_minecraft_username.is_some() {
-                let account = db.minecraft_name_to_uuid(&request.deprecated_minecraft_username.unwrap()).await?;
-                if account.is_some() {
-                    uuid = account.unwrap();
-                } else {
-                    send_change_error(nc.clone(), reply, "Unknown minecraft account.").await?;
+        let uuid = {
+            let uuid = match (request.minecraft_uuid, request.deprecated_minecraft_username) {
+                (Some(uuid), _) => uuid,
+                (None, Some(username)) => match db.minecraft_name_to_uuid(&username).await? {
+                    Some(uuid) => uuid,
+                    None => {
+                        send_change_error(&msg, AccountError::NotFound).await?;
+                        return Ok(());
+                    }
+                },
+                (None, None) => {
+                    send_change_error(&msg, AccountError::InvalidRequest("minecraft_uuid or deprecated_minecraft_username is required".to_string())).await?;
                     return Ok(());
                 }
-            } else {
-                send_change_error(nc.clone(), reply, "Unknown minecraft account.").await?;
-                return Ok(());
-            }
-
-            let (discord, user) = db.uuid_owner(&uuid).await?;
-            let mut owns = false;
-            if discord.is_some() {
-                owns = owns || discord.clone().unwrap().eq(&request.user_id);
-            }
-            if user.is_some() {
-                owns = owns || user.clone().unwrap().eq(&request.user_id);
-            }
+            };
 
-            if !owns {
-                send_change_error(nc.clone(), reply, "Unknown minecraft account.").await?;
+            let owner = match db.uuid_owner(&uuid).await? {
+                Some(owner) => owner,
+                None => {
+                    send_change_error(&msg, AccountError::NotFound).await?;
+                    return Ok(());
+                }
+            };
+            if owner.user_id != request.user_id {
+                send_change_error(&msg, AccountError::NotOwner).await?;
                 return Ok(());
             }
 
-            (uuid, user, discord)
+            uuid
         };
 
-        // get the account to broadcast later
-        let account = db.get_by_minecraft(&uuid).await?.unwrap();
-
-        // Remove whitelist
-        {
-            let mut req = UnwhitelistAccount::new();
-            req.uuid = uuid.clone();
-            let encoded: Vec<u8> = req.write_to_bytes()?;
-            nc.request("minecraft.whitelist.remove", encoded.into()).await?;
-        }
-
-        // Delete account
-        let _deleted = match db.delete_account(&uuid).await {
+        // Delete account, the store queues removing it from the whitelist and the change events
+        let deleted = match db.delete_account(&uuid).await {
             Ok(re) => re,
             Err(e) => {
                 tracing::error!("Error creating account: {:?}", e);
-                send_change_error(nc.clone(), reply, "Internal Error removing account.").await?;
+                send_change_error(&msg, AccountError::Internal("removing account")).await?;
                 return Ok(());
             }
         };
+        if !deleted.deleted {
+            send_change_error(&msg, AccountError::NotFound).await?;
+            return Ok(());
+        }
+
+        // Unwhitelist it
+        let delivery = match deleted.outbox_id {
+            Some(outbox_id) => whitelist::deliver_now(&db, &nc, outbox_id).await,
+            None => Delivery::Queued,
+        };
 
         // Build and Send Response
         let mut resp = ChangeMinecraftAccountResponse::new();
         resp.success = true;
+        resp.error_code = whitelist_code(&delivery).into();
+        resp.error_message = whitelist_warning(&delivery, "Minecraft Account was removed");
         let encoded: Vec<u8> = resp.write_to_bytes()?;
-        nc.publish(reply, encoded.into()).await?;
-
-        // Let's broadcast the account was created.
-        let mut broadcast = MinecraftAccountChanged::new();
-        broadcast.user_id = user_id;
-        broadcast.deprecated_discord_id = discord_id;
-        broadcast.change = MinecraftAccountChangeType::REMOVED.into();
-        broadcast.account = MessageField::some(account);
-        let encoded: Vec<u8> = broadcast.write_to_bytes()?;
-        nc.publish("accounts.minecraft.changed", encoded.into()).await?;
-
-        // do we need to update the users main?
-        // todo - check and update this...
+        msg.respond(encoded.into()).await?;
     }
 
     Ok(())
@@ -0,0 +1,42 @@
+use protobuf::{Message, MessageField};
+use async_nats::Client;
+use std::sync::Arc;
+use crate::handlers::error::AccountError;
+use crate::handlers::util::{check_owner, send_change_error};
+use crate::proto::minecraft_account_set_main::SetMainMinecraftAccountRequest;
+use crate::proto::minecraft_account_update::ChangeMinecraftAccountResponse;
+use crate::store::AccountStore;
+use crate::util::Request;
+
+#[tracing::instrument]
+pub async fn set_main(db: Arc<dyn AccountStore>, nc: Client, msg: Arc<Request>) -> anyhow::Result<()> {
+    let request = SetMainMinecraftAccountRequest::parse_from_bytes(&msg.payload)?;
+
+    if msg.reply.is_some() {
+
+        // Verify Ownership
+        if let Err(error) = check_owner(&db, &request.minecraft_uuid, &request.user_id).await? {
+            send_change_error(&msg, error).await?;
+            return Ok(());
+        }
+
+        // Swap the main flag
+        let account = match db.set_main(&request.minecraft_uuid).await {
+            Ok(account) => account,
+            Err(e) => {
+                tracing::error!("Error setting main account: {:?}", e);
+                send_change_error(&msg, AccountError::Internal("setting main account")).await?;
+                return Ok(());
+            }
+        };
+
+        // Build and Send Response
+        let mut resp = ChangeMinecraftAccountResponse::new();
+        resp.success = true;
+        resp.account = MessageField::from(Some(account));
+        let encoded: Vec<u8> = resp.write_to_bytes()?;
+        msg.respond(encoded.into()).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use protobuf::{Message, MessageField};
+use async_nats::Client;
+use std::sync::Arc;
+use crate::handlers::error::AccountError;
+use sqlx::types::Uuid;
+use crate::handlers::util::{check_owner, lookup_deadline, send_change_error};
+use crate::mojang::{Lookup, MojangClient};
+use crate::proto::minecraft_account_update::{ChangeMinecraftAccountResponse, UpdateMinecraftAccountRequest};
+use crate::store::AccountStore;
+use crate::util::Request;
+
+#[tracing::instrument]
+pub async fn update(db: Arc<dyn AccountStore>, mojang: Arc<dyn MojangClient>, nc: Client, msg: Arc<Request>) -> anyhow::Result<()> {
+    let request = UpdateMinecraftAccountRequest::parse_from_bytes(&msg.payload)?;
+
+    if msg.reply.is_some() {
+
+        // Verify Ownership
+        if let Err(error) = check_owner(&db, &request.minecraft_uuid, &request.user_id).await? {
+            send_change_error(&msg, error).await?;
+            return Ok(());
+        }
+
+        let mut account = match db.get_by_minecraft(&request.minecraft_uuid).await? {
+            Some(account) => account,
+            None => {
+                send_change_error(&msg, AccountError::NotFound).await?;
+                return Ok(());
+            }
+        };
+
+        // An owner always has exactly one main account, so it can only be moved, not unset.
+        if request.is_main == Some(false) && account.is_main {
+            send_change_error(&msg, AccountError::MainAccountRequired).await?;
+            return Ok(());
+        }
+
+        // Renames only write the username, so they can't undo a concurrent main account change.
+        let username = request.minecraft_username.filter(|u| *u != account.minecraft_username);
+        if let Some(username) = username {
+
+            // Only take names mojang has on this account, in mojang's spelling.
+            let profile = match mojang.lookup_username(&username, lookup_deadline(&msg)).await {
+                Ok(Lookup::Found(profile)) => profile,
+                Ok(Lookup::NotFound) => {
+                    send_change_error(&msg, AccountError::UsernameNotFound).await?;
+                    return Ok(());
+                },
+                Ok(Lookup::RateLimited) => {
+                    send_change_error(&msg, AccountError::MojangRateLimited).await?;
+                    return Ok(());
+                },
+                Err(e) => {
+                    tracing::error!("Error looking up username: {:?}", e);
+                    send_change_error(&msg, AccountError::MojangUnavailable).await?;
+                    return Ok(());
+                },
+            };
+            if Uuid::parse_str(&profile.id)? != Uuid::parse_str(&account.minecraft_uuid)? {
+                send_change_error(&msg, AccountError::UsernameMismatch).await?;
+                return Ok(());
+            }
+
+            // Only the spelling the caller sent differed.
+            if profile.name != account.minecraft_username {
+                account = match db.rename_account(&account.minecraft_uuid, &profile.name).await {
+                    Ok(account) => account,
+                    Err(e) => {
+                        tracing::error!("Error updating account: {:?}", e);
+                        send_change_error(&msg, AccountError::Internal("updating account")).await?;
+                        return Ok(());
+                    }
+                };
+            }
+        }
+
+        // Moving the main account has to swap both flags at once. It happens after the rename, so a
+        // rename that fails doesn't leave the main account moved.
+        if request.is_main == Some(true) && !account.is_main {
+            account = match db.set_main(&account.minecraft_uuid).await {
+                Ok(account) => account,
+                Err(e) => {
+                    tracing::error!("Error setting main account: {:?}", e);
+                    send_change_error(&msg, AccountError::Internal("updating account")).await?;
+                    return Ok(());
+                }
+            };
+        }
+
+        // Build and Send Response
+        let mut resp = ChangeMinecraftAccountResponse::new();
+        resp.success = true;
+        resp.account = MessageField::from(Some(account.clone()));
+        let encoded: Vec<u8> = resp.write_to_bytes()?;
+        msg.respond(encoded.into()).await?;
+    }
+
+    Ok(())
+}
@@ -1,13 +1,61 @@
-use protobuf::{Message};
+use protobuf::Message;
 use anyhow::Result;
-use async_nats::{Client, Subject};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::handlers::error::AccountError;
+use crate::proto::minecraft_account_error::MinecraftAccountErrorCode;
 use crate::proto::minecraft_account_update::ChangeMinecraftAccountResponse;
+use crate::store::{AccountOwner, AccountStore};
+use crate::util::Request;
+use crate::whitelist::{Delivery, WhitelistError};
 
-pub async fn send_change_error(nc: Client, sub: Subject, message: &str) -> Result<()> {
+/// Time a handler keeps after its mojang lookups to store the change and reply.
+const REPLY_RESERVE: Duration = Duration::from_millis(200);
+
+/// How long the handler's mojang lookups may wait and retry, so it still replies before its deadline.
+pub fn lookup_deadline(msg: &Request) -> Instant {
+    msg.deadline.checked_sub(REPLY_RESERVE).unwrap_or(msg.deadline)
+}
+
+pub async fn send_change_error(msg: &Request, error: AccountError) -> Result<()> {
     let mut resp = ChangeMinecraftAccountResponse::new();
     resp.success = false;
-    resp.error_message = Some(message.to_string());
+    resp.error_code = error.code().into();
+    resp.error_message = Some(error.to_string());
     let encoded: Vec<u8> = resp.write_to_bytes()?;
-    nc.publish(sub, encoded.into()).await?;
+    msg.respond(encoded.into()).await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// The owner of the minecraft account if it is the given user, or the error to reply with.
+pub async fn check_owner(db: &Arc<dyn AccountStore>, minecraft_uuid: &str, user_id: &str) -> Result<std::result::Result<AccountOwner, AccountError>> {
+    Ok(match db.uuid_owner(minecraft_uuid).await? {
+        None => Err(AccountError::NotFound),
+        Some(owner) if owner.user_id == user_id => Ok(owner),
+        Some(_) => Err(AccountError::NotOwner),
+    })
+}
+
+/// Explains a whitelist change that didn't go through, the account change itself was saved.
+pub fn whitelist_warning(delivery: &Delivery, done: &str) -> Option<String> {
+    match delivery {
+        Delivery::Delivered | Delivery::Queued => None,
+        Delivery::Failed(WhitelistError::Timeout) =>
+            Some(format!("{}, but the whitelist request timed out. It will be retried.", done)),
+        Delivery::Failed(WhitelistError::Rejected(message)) =>
+            Some(format!("{}, but the game server rejected the whitelist request: {}. It will be retried.", done, message)),
+        Delivery::Failed(WhitelistError::Failed(_)) =>
+            Some(format!("{}, but the whitelist request failed. It will be retried.", done)),
+    }
+}
+
+/// The code to go with [`whitelist_warning`], so clients can tell a saved change that
+/// isn't on the whitelist yet from one that is.
+pub fn whitelist_code(delivery: &Delivery) -> MinecraftAccountErrorCode {
+    match delivery {
+        Delivery::Delivered | Delivery::Queued => MinecraftAccountErrorCode::NONE,
+        Delivery::Failed(WhitelistError::Timeout) => MinecraftAccountErrorCode::WHITELIST_TIMEOUT,
+        Delivery::Failed(WhitelistError::Rejected(_) | WhitelistError::Failed(_)) => MinecraftAccountErrorCode::WHITELIST_FAILED,
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::HashSet;
+use anyhow::Result;
+use async_nats::Client;
+use std::sync::Arc;
+use crate::store::AccountStore;
+use crate::users;
+
+/// Resolves the legacy discord ids that own accounts without a user id through the users service.
+/// With `apply`, gives their accounts to the user, the store records them as updated.
+///
+/// Accounts no user can be found for are reported, and with `apply` and `delete_unresolved`
+/// deleted, so the user_id migration can run without them.
+pub async fn backfill_user_ids(db: Arc<dyn AccountStore>, nc: Client, apply: bool, delete_unresolved: bool) -> Result<()> {
+    let discord_ids = db.discord_only_owners().await?;
+    tracing::info!("{} discord ids own accounts without a user id", discord_ids.len());
+
+    let mut resolved = 0;
+    let mut failed = 0;
+    let mut unknown = HashSet::new();
+    for discord_id in &discord_ids {
+        let user_id = match users::user_id_for_discord(&nc, discord_id).await {
+            Ok(Some(user_id)) => user_id,
+            Ok(None) => {
+                tracing::warn!("No user has discord id {}", discord_id);
+                unknown.insert(discord_id.clone());
+                continue;
+            },
+            Err(e) => {
+                tracing::error!("Error looking up the user for discord id {}: {:?}", discord_id, e);
+                failed += 1;
+                continue;
+            },
+        };
+
+        if !apply {
+            tracing::info!("discord id {} is user {}", discord_id, user_id);
+            resolved += 1;
+            continue;
+        }
+
+        db.assign_user_id(discord_id, &user_id).await?;
+        resolved += 1;
+    }
+
+    // Accounts of discord ids no user has, and any without a discord id either.
+    let orphans: Vec<_> = db.accounts_without_user_id().await?.into_iter()
+        .filter(|l| l.discord_id.as_ref().is_none_or(|d| unknown.contains(d)))
+        .collect();
+    let mut deleted = 0;
+    for orphan in &orphans {
+        tracing::warn!("{} ({}) of discord id {:?} has no user",
+            orphan.account.minecraft_uuid, orphan.account.minecraft_username, orphan.discord_id);
+        if !(apply && delete_unresolved) {
+            continue;
+        }
+
+        // The store queues unwhitelisting it and its REMOVED event for the outbox jobs.
+        if db.delete_legacy_account(&orphan.account.minecraft_uuid).await? {
+            deleted += 1;
+        }
+    }
+
+    if !apply {
+        tracing::info!("Dry run, pass --apply to give the accounts of {} discord ids to their user", resolved);
+    } else {
+        tracing::info!("Gave the accounts of {} discord ids to their user, deleted {} accounts without a user", resolved, deleted);
+    }
+
+    // The user_id migration refuses to run while any accounts without a user id are left.
+    let remaining = orphans.len() - deleted;
+    if failed > 0 || remaining > 0 {
+        return Err(anyhow::anyhow!(
+            "{} discord ids could not be looked up and {} accounts have no user, pass --delete-unresolved to delete those",
+            failed, remaining,
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use std::time::Duration;
+use anyhow::Result;
+use async_nats::Client;
+use std::sync::Arc;
+use crate::events;
+use crate::store::AccountStore;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_SIZE: i64 = 50;
+const LEASE: Duration = Duration::from_secs(30);
+const MAX_BACKOFF_SECS: u64 = 5 * 60;
+
+/// Publishes the change events recorded alongside account changes, in the order they were recorded
+/// for each account, retrying failures with backoff so no committed change goes unannounced.
+pub async fn event_outbox(db: Arc<dyn AccountStore>, nc: Client) -> Result<()> {
+    loop {
+        let entries = match db.claim_events(BATCH_SIZE, LEASE).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::error!("Error claiming change events: {:?}", e);
+                Vec::new()
+            }
+        };
+        let full = entries.len() == BATCH_SIZE as usize;
+
+        for entry in entries {
+            // The lease runs out and the event is picked up again if recording fails.
+            let recorded = match events::publish_change(&nc, entry.id, &entry.payload).await {
+                Ok(()) => db.event_published(entry.id).await,
+                Err(e) => {
+                    let retry_in = Duration::from_secs((1u64 << entry.attempts.clamp(0, 16)).min(MAX_BACKOFF_SECS));
+                    tracing::warn!("Error publishing change event {}, retrying in {:?}: {:?}", entry.id, retry_in, e);
+                    db.event_failed(entry.id, &e.to_string(), retry_in).await
+                },
+            };
+            if let Err(e) = recorded {
+                tracing::error!("Error recording change event delivery: {:?}", e);
+            }
+        }
+
+        // A full batch likely means more are waiting.
+        if !full {
+            db.events_notified(POLL_INTERVAL).await;
+        }
+    }
+}
@@ -0,0 +1,6 @@
+pub mod backfill_user_ids;
+pub mod event_outbox;
+pub mod reconcile;
+pub mod republish;
+pub mod username_sync;
+pub mod whitelist_outbox;
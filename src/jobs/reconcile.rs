@@ -0,0 +1,65 @@
+use std::collections::HashSet;
+use std::env;
+use std::time::Duration;
+use anyhow::Result;
+use async_nats::Client;
+use std::sync::Arc;
+use crate::store::AccountStore;
+use crate::whitelist::{self, WhitelistAction};
+
+const DEFAULT_LIST_WINDOW_MS: u64 = 2000;
+
+/// Compares every game server's whitelist with the accounts table and reports the differences.
+/// With `apply`, sends the whitelist changes needed to bring the servers back in line.
+pub async fn reconcile(db: Arc<dyn AccountStore>, nc: Client, apply: bool) -> Result<()> {
+    let window = env::var("WHITELIST_LIST_WINDOW_MS").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_LIST_WINDOW_MS);
+    reconcile_with_window(db, nc, apply, Duration::from_millis(window)).await
+}
+
+/// [`reconcile`] waiting `window` for the game servers to list their whitelist.
+pub async fn reconcile_with_window(db: Arc<dyn AccountStore>, nc: Client, apply: bool, window: Duration) -> Result<()> {
+    let accounts: HashSet<String> = db.all_uuids().await?.into_iter().collect();
+    let servers = whitelist::list(&nc, window).await?;
+    if servers.is_empty() {
+        return Err(anyhow::anyhow!("No game servers replied with their whitelist."));
+    }
+
+    let mut to_add = HashSet::new();
+    let mut to_remove = HashSet::new();
+    for (server, whitelisted) in &servers {
+        let missing: Vec<&String> = accounts.difference(whitelisted).collect();
+        let orphaned: Vec<&String> = whitelisted.difference(&accounts).collect();
+
+        tracing::info!("{}: {} whitelisted, {} accounts not whitelisted, {} whitelisted without an account",
+            server, whitelisted.len(), missing.len(), orphaned.len());
+        for uuid in &missing {
+            tracing::info!("{}: not whitelisted {}", server, uuid);
+        }
+        for uuid in &orphaned {
+            tracing::info!("{}: no account for {}", server, uuid);
+        }
+
+        to_add.extend(missing.into_iter().cloned());
+        to_remove.extend(orphaned.into_iter().cloned());
+    }
+
+    if !apply {
+        tracing::info!("Dry run, pass --apply to whitelist {} and unwhitelist {} accounts", to_add.len(), to_remove.len());
+        return Ok(());
+    }
+
+    let mut failed = 0;
+    let changes = to_add.iter().map(|u| (WhitelistAction::Add, u))
+        .chain(to_remove.iter().map(|u| (WhitelistAction::Remove, u)));
+    for (action, uuid) in changes {
+        if let Err(e) = whitelist::send(&nc, action, uuid).await {
+            tracing::error!("Error sending whitelist {} for {}: {:?}", action, uuid, e);
+            failed += 1;
+        }
+    }
+
+    tracing::info!("Whitelisted {} and unwhitelisted {} accounts, {} failed", to_add.len(), to_remove.len(), failed);
+    Ok(())
+}
@@ -0,0 +1,18 @@
+use anyhow::Result;
+use std::sync::Arc;
+use crate::store::AccountStore;
+
+const BATCH_SIZE: i64 = 500;
+
+/// Records an UPDATED event with the current state of every account, so consumers catch up on
+/// changes made without events, like the one_main_account migration picking a main account.
+/// The service publishes them once it runs.
+pub async fn republish(db: Arc<dyn AccountStore>) -> Result<()> {
+    let mut after_id = 0;
+    while let Some(last_id) = db.republish_page(after_id, BATCH_SIZE).await? {
+        after_id = last_id;
+    }
+
+    tracing::info!("Recorded change events for every account up to id {}", after_id);
+    Ok(())
+}
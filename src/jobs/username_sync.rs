@@ -0,0 +1,147 @@
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+use tokio::time::Instant;
+use crate::mojang::{HttpMojangClient, Lookup, MojangClient};
+use crate::store::AccountStore;
+
+const DEFAULT_INTERVAL_SECS: u64 = 6 * 60 * 60;
+const DEFAULT_BATCH_SIZE: i64 = 100;
+// Its share of mojang's 600 requests every 10 minutes, the handlers get the rest.
+const DEFAULT_RATE_LIMIT: u32 = 100;
+// The sync has a limiter of its own, so it can wait for it instead of ending the run.
+const LOOKUP_BUDGET: Duration = Duration::from_secs(30);
+
+/// The sync's own mojang client, limited to USERNAME_SYNC_RATE_LIMIT requests per
+/// MOJANG_RATE_LIMIT_WINDOW_SECS so it never uses up the handlers' requests.
+pub fn mojang_client() -> HttpMojangClient {
+    HttpMojangClient::from_env_with_limit("USERNAME_SYNC_RATE_LIMIT", DEFAULT_RATE_LIMIT)
+}
+
+/// Periodically refreshes stored usernames from the current mojang profile names.
+/// Runs every USERNAME_SYNC_INTERVAL_SECS, walking USERNAME_SYNC_BATCH_SIZE accounts at a time.
+/// A run that hits the rate limit is continued by the next one, a new pass starts once every
+/// account was looked at.
+pub async fn username_sync(db: Arc<dyn AccountStore>, mojang: Arc<dyn MojangClient>) -> Result<()> {
+    let interval = env::var("USERNAME_SYNC_INTERVAL_SECS").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_INTERVAL_SECS);
+    let batch_size = env::var("USERNAME_SYNC_BATCH_SIZE").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_BATCH_SIZE);
+
+    let interval = Duration::from_secs(interval);
+    let mut ticker = tokio::time::interval_at(Instant::now() + interval, interval);
+    let mut after_id = 0;
+
+    loop {
+        ticker.tick().await;
+        match sync_all(&db, &mojang, batch_size, &mut after_id).await {
+            Ok(renamed) => tracing::info!("Username sync finished, {} accounts renamed", renamed),
+            Err(e) => tracing::error!("Username sync failed: {:?}", e),
+        }
+    }
+}
+
+/// Looks at the accounts after `after_id`, moving it past each one. Puts it back to the start
+/// once the last account was looked at.
+async fn sync_all(db: &Arc<dyn AccountStore>, mojang: &Arc<dyn MojangClient>, batch_size: i64, after_id: &mut i64) -> Result<usize> {
+    let mut renamed = 0;
+
+    loop {
+        let page = db.get_page(*after_id, batch_size).await?;
+        if page.is_empty() {
+            *after_id = 0;
+            return Ok(renamed);
+        }
+
+        for stored in page {
+            let account = stored.account;
+
+            let profile = match mojang.lookup_uuid(&account.minecraft_uuid, Instant::now() + LOOKUP_BUDGET).await {
+                Ok(Lookup::Found(profile)) => profile,
+                Ok(Lookup::NotFound) => {
+                    tracing::warn!("No mojang profile for {}", account.minecraft_uuid);
+                    *after_id = stored.id;
+                    continue;
+                },
+                Ok(Lookup::RateLimited) => {
+                    // Pick up where we left off on the next run.
+                    tracing::warn!("Mojang rate limit reached during username sync");
+                    return Ok(renamed);
+                },
+                Err(e) => {
+                    tracing::error!("Error looking up {}: {:?}", account.minecraft_uuid, e);
+                    *after_id = stored.id;
+                    continue;
+                },
+            };
+            *after_id = stored.id;
+
+            if profile.name == account.minecraft_username {
+                continue;
+            }
+
+            tracing::info!("{} renamed from {} to {}", account.minecraft_uuid, account.minecraft_username, profile.name);
+
+            // Can fail if another stored account still has the name, it will be retried next pass.
+            // Only the name is written, is_main may have changed since the page was read. The store
+            // records the UPDATED event with the rename.
+            if let Err(e) = db.rename_account(&account.minecraft_uuid, &profile.name).await {
+                tracing::error!("Error renaming {}: {:?}", account.minecraft_uuid, e);
+                continue;
+            }
+            renamed += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mojang::fake::FakeMojang;
+    use crate::mojang::limiter::RateLimiter;
+    use crate::proto::minecraft_account::MinecraftAccount;
+    use crate::store::memory::MemoryStore;
+
+    const PROFILES: [(&str, &str, &str); 3] = [
+        ("069a79f4-44e9-4726-a5be-fca90e38aaf5", "069a79f444e94726a5befca90e38aaf5", "Notch"),
+        ("853c80ef-3c37-49fd-aa49-938b674adae6", "853c80ef3c3749fdaa49938b674adae6", "jeb_"),
+        ("61699b2e-d327-4a01-9f1e-0ea8c3f06bc6", "61699b2ed3274a019f1e0ea8c3f06bc6", "Dinnerbone"),
+    ];
+
+    /// A client that can only send `requests` before its limiter runs dry.
+    fn client(fake: &FakeMojang, requests: u32) -> Arc<dyn MojangClient> {
+        let limiter = Arc::new(RateLimiter::new(requests, 0.001));
+        Arc::new(HttpMojangClient::new(&fake.url(), &fake.url(), limiter))
+    }
+
+    #[tokio::test]
+    async fn rate_limited_runs_continue_where_they_stopped() {
+        let fake = FakeMojang::start().await;
+        let store = Arc::new(MemoryStore::new());
+        for (uuid, id, name) in PROFILES {
+            let mut account = MinecraftAccount::new();
+            account.minecraft_uuid = uuid.to_string();
+            account.minecraft_username = format!("old_{}", name);
+            store.add_account("user", None, &account).await.unwrap();
+            fake.add_profile(id, name);
+        }
+        let db: Arc<dyn AccountStore> = store.clone();
+        let mut after_id = 0;
+
+        // Everything is on one page, the second run still starts at the third account.
+        assert_eq!(sync_all(&db, &client(&fake, 2), 100, &mut after_id).await.unwrap(), 2);
+        assert_ne!(after_id, 0);
+        assert_eq!(db.get_by_minecraft(PROFILES[2].0).await.unwrap().unwrap().minecraft_username, "old_Dinnerbone");
+
+        let requests = fake.requests();
+        assert_eq!(sync_all(&db, &client(&fake, 2), 100, &mut after_id).await.unwrap(), 1);
+        assert_eq!(fake.requests() - requests, 1);
+        assert_eq!(db.get_by_minecraft(PROFILES[2].0).await.unwrap().unwrap().minecraft_username, "Dinnerbone");
+
+        // The pass is done, the next one starts over.
+        assert_eq!(after_id, 0);
+    }
+}
@@ -0,0 +1,43 @@
+use std::time::Duration;
+use anyhow::Result;
+use async_nats::Client;
+use std::sync::Arc;
+use crate::store::AccountStore;
+use crate::whitelist;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_SIZE: i64 = 50;
+const LEASE: Duration = Duration::from_secs(30);
+
+/// Delivers the whitelist changes queued alongside account changes, retrying failures with
+/// backoff so the whitelist always ends up matching the accounts table.
+pub async fn whitelist_outbox(db: Arc<dyn AccountStore>, nc: Client) -> Result<()> {
+    whitelist_outbox_with_interval(db, nc, POLL_INTERVAL).await
+}
+
+/// [`whitelist_outbox`] checking for due changes every `poll_interval`.
+pub async fn whitelist_outbox_with_interval(db: Arc<dyn AccountStore>, nc: Client, poll_interval: Duration) -> Result<()> {
+    loop {
+        let entries = match db.claim_whitelist(BATCH_SIZE, LEASE).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::error!("Error claiming whitelist changes: {:?}", e);
+                Vec::new()
+            }
+        };
+        let full = entries.len() == BATCH_SIZE as usize;
+
+        for entry in entries {
+            let result = whitelist::send(&nc, entry.action, &entry.minecraft_uuid).await;
+            // The lease runs out and the change is picked up again.
+            if let Err(e) = whitelist::record(&db, &entry, &result).await {
+                tracing::error!("Error recording whitelist delivery: {:?}", e);
+            }
+        }
+
+        // A full batch likely means more are waiting.
+        if !full {
+            db.outbox_notified(poll_interval).await;
+        }
+    }
+}
@@ -2,14 +2,36 @@ mod proto;
 mod util;
 mod store;
 mod handlers;
+mod events;
+mod jobs;
+mod mojang;
+mod whitelist;
+mod users;
+#[cfg(test)]
+mod e2e;
 
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
 use anyhow::Result;
+use async_nats::service::Service;
+use tokio::sync::watch;
 use tokio::task::JoinSet;
 use crate::handlers::add::add;
 use crate::handlers::get::get;
+use crate::handlers::history::history;
 use crate::handlers::list::list;
 use crate::handlers::remove::remove;
-use crate::store::Store;
+use crate::handlers::set_main::set_main;
+use crate::handlers::update::update;
+use crate::jobs::backfill_user_ids::backfill_user_ids;
+use crate::jobs::event_outbox::event_outbox;
+use crate::jobs::reconcile::reconcile;
+use crate::jobs::republish::republish;
+use crate::jobs::username_sync::username_sync;
+use crate::jobs::whitelist_outbox::whitelist_outbox;
+use crate::mojang::{BatchingMojangClient, CachedMojangClient, HttpMojangClient, MojangClient};
+use crate::store::{AccountStore, Store};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -23,47 +45,176 @@ async fn main() -> Result<()> {
     // Setup logging
     util::setup_logging(app_name.as_str());
 
-    // connect to db
-    let db = util::connect_to_database().await?;
+    // one off commands
+    let args: Vec<String> = env::args().collect();
+    let command = args.get(1).map(|a| a.as_str());
+
+    // connect to db, the backfill has to run before the user_id migration can be applied
+    let db = match command {
+        Some("backfill-user-ids") => util::connect_to_database_for_backfill().await?,
+        _ => util::connect_to_database().await?,
+    };
     let store = Store::new(db.clone());
+    let accounts: Arc<dyn AccountStore> = Arc::new(store.clone());
+
+    // mojang api used for username lookups
+    let mojang: Arc<dyn MojangClient> = Arc::new(CachedMojangClient::from_env(
+        Arc::new(BatchingMojangClient::from_env(Arc::new(HttpMojangClient::from_env()))),
+        store.clone(),
+    ));
 
     // connect to nats
     let nc = util::connect_to_nats().await?;
 
+    // change events are kept in jetstream so consumers can replay them
+    events::create_stream(&nc).await?;
+
+    if command == Some("reconcile") {
+        reconcile(accounts.clone(), nc.clone(), args.iter().any(|a| a == "--apply")).await?;
+        nc.flush().await?;
+        return Ok(());
+    }
+    if command == Some("republish") {
+        republish(accounts.clone()).await?;
+        return Ok(());
+    }
+    if command == Some("backfill-user-ids") {
+        let delete_unresolved = args.iter().any(|a| a == "--delete-unresolved");
+        backfill_user_ids(accounts.clone(), nc.clone(), args.iter().any(|a| a == "--apply"), delete_unresolved).await?;
+        nc.flush().await?;
+        return Ok(());
+    }
+
+    // register with the nats service api, replicas share requests through the queue group
+    let queue_group = util::get_queue_group(&app_name);
+    let service = Arc::new(util::start_service(&nc, &app_name, &queue_group).await?);
+
+    // flips to true on SIGTERM or ctrl-c
+    let (shutdown_tx, shutdown) = watch::channel(false);
+
     let mut set = JoinSet::new();
+    spawn_handlers(&mut set, &nc, &service, &accounts, &mojang, &shutdown);
+
+    // background jobs are safe to stop at any point, every change they make is committed along
+    // with its events and whitelist changes, and the outbox jobs pick up where they left off
+    let mut jobs = JoinSet::new();
+
+    let _store = accounts.clone();
+    let _mojang: Arc<dyn MojangClient> = Arc::new(jobs::username_sync::mojang_client());
+    jobs.spawn(async move {
+        username_sync(_store, _mojang).await.expect("username sync");
+    });
+
+    let _nc = nc.clone();
+    let _store = accounts.clone();
+    jobs.spawn(async move {
+        event_outbox(_store, _nc).await.expect("event outbox");
+    });
+
+    let _nc = nc.clone();
+    let _store = accounts.clone();
+    jobs.spawn(async move {
+        whitelist_outbox(_store, _nc).await.expect("whitelist outbox");
+    });
+
+    tokio::select! {
+        result = util::shutdown_signal() => result?,
+        Some(result) = set.join_next() => tracing::error!("A handler stopped unexpectedly: {:?}", result),
+    }
+
+    // Stop taking requests and let the ones in flight finish
+    tracing::info!("Shutting down");
+    let _ = shutdown_tx.send(true);
+    set.join_all().await;
+    jobs.shutdown().await;
+
+    if let Ok(service) = Arc::try_unwrap(service) {
+        service.stop().await.map_err(|e| anyhow::anyhow!(e))?;
+    }
+    nc.flush().await?;
+    db.close().await;
+
+    Ok(())
+}
 
+/// Serves every request subject through the service, until shutdown.
+fn spawn_handlers(
+    set: &mut JoinSet<()>,
+    nc: &async_nats::Client,
+    service: &Arc<Service>,
+    accounts: &Arc<dyn AccountStore>,
+    mojang: &Arc<dyn MojangClient>,
+    shutdown: &watch::Receiver<bool>,
+) {
     let _nc = nc.clone();
-    let _store = store.clone();
+    let _store = accounts.clone();
+    let _mojang = mojang.clone();
+    let _service = service.clone();
+    let _shutdown = shutdown.clone();
     set.spawn(async move {
-        util::handle_requests(_nc, "accounts.minecraft.add", move|_nc, msg| {
-            add(_store.clone(), _nc, msg)
+        util::handle_change_requests(_nc, &_service, "add", "accounts.minecraft.add", Duration::from_millis(2000), _shutdown, move|_nc, msg| {
+            add(_store.clone(), _mojang.clone(), _nc, msg)
         }).await.expect("accounts.minecraft.add");
     });
 
     let _nc = nc.clone();
-    let _store = store.clone();
+    let _store = accounts.clone();
+    let _service = service.clone();
+    let _shutdown = shutdown.clone();
     set.spawn(async move {
-        util::handle_requests(_nc, "accounts.minecraft.remove", move|_nc, msg| {
+        util::handle_change_requests(_nc, &_service, "remove", "accounts.minecraft.remove", Duration::from_millis(1000), _shutdown, move|_nc, msg| {
             remove(_store.clone(), _nc, msg)
         }).await.expect("accounts.minecraft.remove");
     });
 
     let _nc = nc.clone();
-    let _store = store.clone();
+    let _store = accounts.clone();
+    let _service = service.clone();
+    let _shutdown = shutdown.clone();
     set.spawn(async move {
-        util::handle_requests(_nc, "accounts.minecraft.list", move|_nc, msg| {
+        util::handle_requests(_nc, &_service, "list", "accounts.minecraft.list", Duration::from_millis(1000), _shutdown, move|_nc, msg| {
             list(_store.clone(), _nc, msg)
         }).await.expect("accounts.minecraft.list");
     });
 
     let _nc = nc.clone();
-    let _store = store.clone();
+    let _store = accounts.clone();
+    let _service = service.clone();
+    let _shutdown = shutdown.clone();
     set.spawn(async move {
-        util::handle_requests(_nc, "accounts.minecraft.get", move|_nc, msg| {
+        util::handle_requests(_nc, &_service, "get", "accounts.minecraft.get", Duration::from_millis(1000), _shutdown, move|_nc, msg| {
             get(_store.clone(), _nc, msg)
         }).await.expect("accounts.minecraft.get");
     });
 
-    set.join_all().await;
-    Ok(())
+    let _nc = nc.clone();
+    let _store = accounts.clone();
+    let _mojang = mojang.clone();
+    let _service = service.clone();
+    let _shutdown = shutdown.clone();
+    set.spawn(async move {
+        util::handle_change_requests(_nc, &_service, "update", "accounts.minecraft.update", Duration::from_millis(2000), _shutdown, move|_nc, msg| {
+            update(_store.clone(), _mojang.clone(), _nc, msg)
+        }).await.expect("accounts.minecraft.update");
+    });
+
+    let _nc = nc.clone();
+    let _store = accounts.clone();
+    let _service = service.clone();
+    let _shutdown = shutdown.clone();
+    set.spawn(async move {
+        util::handle_change_requests(_nc, &_service, "set_main", "accounts.minecraft.set_main", Duration::from_millis(1000), _shutdown, move|_nc, msg| {
+            set_main(_store.clone(), _nc, msg)
+        }).await.expect("accounts.minecraft.set_main");
+    });
+
+    let _nc = nc.clone();
+    let _store = accounts.clone();
+    let _service = service.clone();
+    let _shutdown = shutdown.clone();
+    set.spawn(async move {
+        util::handle_requests(_nc, &_service, "history", "accounts.minecraft.history", Duration::from_millis(1000), _shutdown, move|_nc, msg| {
+            history(_store.clone(), _nc, msg)
+        }).await.expect("accounts.minecraft.history");
+    });
 }
@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use crate::mojang::{is_valid_username, Lookup, MojangClient};
+use crate::mojang::http::MAX_BULK_USERNAMES;
+
+const DEFAULT_BATCH_WINDOW_MS: u64 = 25;
+
+#[derive(Debug)]
+struct Pending {
+    username: String,
+    deadline: Instant,
+    reply: oneshot::Sender<Result<Lookup, String>>,
+}
+
+/// Coalesces concurrent single lookups into bulk lookups of up to ten usernames,
+/// waiting at most `window` after the first username for others to join.
+#[derive(Debug)]
+pub struct BatchingMojangClient {
+    inner: Arc<dyn MojangClient>,
+    pending: mpsc::UnboundedSender<Pending>,
+}
+
+impl BatchingMojangClient {
+
+    pub fn new(inner: Arc<dyn MojangClient>, window: Duration) -> Self {
+        let (pending, rx) = mpsc::unbounded_channel();
+        tokio::spawn(collect(inner.clone(), rx, window));
+        BatchingMojangClient { inner, pending }
+    }
+
+    /// Uses MOJANG_BATCH_WINDOW_MS as the window.
+    pub fn from_env(inner: Arc<dyn MojangClient>) -> Self {
+        let window = env::var("MOJANG_BATCH_WINDOW_MS").ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_BATCH_WINDOW_MS);
+        Self::new(inner, Duration::from_millis(window))
+    }
+}
+
+async fn collect(inner: Arc<dyn MojangClient>, mut rx: mpsc::UnboundedReceiver<Pending>, window: Duration) {
+    while let Some(first) = rx.recv().await {
+        let mut batch = vec![first];
+        let deadline = Instant::now() + window;
+
+        while batch.len() < MAX_BULK_USERNAMES {
+            match tokio::time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(pending)) => batch.push(pending),
+                _ => break,
+            }
+        }
+
+        tokio::spawn(resolve(inner.clone(), batch));
+    }
+}
+
+async fn resolve(inner: Arc<dyn MojangClient>, batch: Vec<Pending>) {
+    // The same name can be asked for by several callers.
+    let mut usernames: Vec<String> = Vec::new();
+    for pending in &batch {
+        let key = pending.username.to_lowercase();
+        if !usernames.iter().any(|u| u.to_lowercase() == key) {
+            usernames.push(pending.username.clone());
+        }
+    }
+
+    // The bulk lookup has to answer the most impatient caller in time.
+    let deadline = batch.iter().map(|p| p.deadline).min().expect("batches are never empty");
+
+    match inner.lookup_usernames(&usernames, deadline).await {
+        Ok(lookups) => {
+            let lookups: HashMap<String, Lookup> = usernames.iter()
+                .map(|u| u.to_lowercase())
+                .zip(lookups)
+                .collect();
+            for pending in batch {
+                let lookup = lookups.get(&pending.username.to_lowercase()).cloned().unwrap_or(Lookup::NotFound);
+                let _ = pending.reply.send(Ok(lookup));
+            }
+        },
+        Err(e) => {
+            tracing::error!("Error looking up usernames: {:?}", e);
+            for pending in batch {
+                let _ = pending.reply.send(Err(e.to_string()));
+            }
+        },
+    }
+}
+
+#[async_trait]
+impl MojangClient for BatchingMojangClient {
+
+    async fn lookup_username(&self, username: &str, deadline: Instant) -> Result<Lookup> {
+        // No one has it, and it would fail the bulk lookup for everyone else in the batch.
+        if !is_valid_username(username) {
+            return Ok(Lookup::NotFound);
+        }
+
+        let (reply, rx) = oneshot::channel();
+        self.pending.send(Pending { username: username.to_string(), deadline, reply })?;
+        rx.await?.map_err(|e| anyhow::anyhow!(e))
+    }
+
+    async fn lookup_uuid(&self, uuid: &str, deadline: Instant) -> Result<Lookup> {
+        self.inner.lookup_uuid(uuid, deadline).await
+    }
+
+    async fn lookup_usernames(&self, usernames: &[String], deadline: Instant) -> Result<Vec<Lookup>> {
+        self.inner.lookup_usernames(usernames, deadline).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mojang::fake::FakeMojang;
+    use crate::mojang::HttpMojangClient;
+    use crate::mojang::limiter::RateLimiter;
+
+    fn soon() -> Instant {
+        Instant::now() + Duration::from_millis(200)
+    }
+
+    #[tokio::test]
+    async fn concurrent_lookups_share_one_request() {
+        let fake = FakeMojang::start().await;
+        fake.add_profile("069a79f444e94726a5befca90e38aaf5", "Notch");
+        fake.add_profile("853c80ef3c3749fdaa49938b674adae6", "jeb_");
+        let limiter = Arc::new(RateLimiter::new(100, 100.0));
+        let inner = Arc::new(HttpMojangClient::new(&fake.url(), &fake.url(), limiter));
+        let client = BatchingMojangClient::new(inner, Duration::from_millis(50));
+
+        let (notch, jeb, nobody, notch_again) = tokio::join!(
+            client.lookup_username("Notch", soon()),
+            client.lookup_username("jeb_", soon()),
+            client.lookup_username("Nobody", soon()),
+            client.lookup_username("notch", soon()),
+        );
+
+        assert!(matches!(notch.unwrap(), Lookup::Found(p) if p.name == "Notch"));
+        assert!(matches!(jeb.unwrap(), Lookup::Found(p) if p.name == "jeb_"));
+        assert_eq!(nobody.unwrap(), Lookup::NotFound);
+        assert!(matches!(notch_again.unwrap(), Lookup::Found(p) if p.name == "Notch"));
+        assert_eq!(fake.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn invalid_names_dont_fail_the_batch() {
+        let fake = FakeMojang::start().await;
+        fake.add_profile("069a79f444e94726a5befca90e38aaf5", "Notch");
+        let limiter = Arc::new(RateLimiter::new(100, 100.0));
+        let inner = Arc::new(HttpMojangClient::new(&fake.url(), &fake.url(), limiter));
+        let client = BatchingMojangClient::new(inner, Duration::from_millis(50));
+
+        let (notch, invalid) = tokio::join!(
+            client.lookup_username("Notch", soon()),
+            client.lookup_username("not a name!", soon()),
+        );
+
+        assert!(matches!(notch.unwrap(), Lookup::Found(p) if p.name == "Notch"));
+        assert_eq!(invalid.unwrap(), Lookup::NotFound);
+        assert_eq!(fake.requests(), 1);
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::Utc;
+use tokio::time::Instant;
+use crate::mojang::{Lookup, MojangClient};
+use crate::store::Store;
+
+const DEFAULT_TTL_SECS: u64 = 60 * 60;
+const DEFAULT_NEGATIVE_TTL_SECS: u64 = 5 * 60;
+
+// Expired entries are only swept once the cache grows past this.
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Debug)]
+struct Entry {
+    lookup: Lookup,
+    expires: Instant,
+}
+
+/// Caches found usernames for `ttl` and unknown usernames for `negative_ttl`
+/// in memory, and optionally in postgres so restarts and replicas share it.
+#[derive(Debug)]
+pub struct CachedMojangClient {
+    inner: Arc<dyn MojangClient>,
+    entries: Mutex<HashMap<String, Entry>>,
+    ttl: Duration,
+    negative_ttl: Duration,
+    store: Option<Store>,
+}
+
+impl CachedMojangClient {
+
+    pub fn new(inner: Arc<dyn MojangClient>, ttl: Duration, negative_ttl: Duration, store: Option<Store>) -> Self {
+        CachedMojangClient {
+            inner,
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+            negative_ttl,
+            store,
+        }
+    }
+
+    /// Uses MOJANG_CACHE_TTL_SECS, MOJANG_CACHE_NEGATIVE_TTL_SECS, and
+    /// persists to postgres when MOJANG_CACHE_PERSIST is true.
+    pub fn from_env(inner: Arc<dyn MojangClient>, store: Store) -> Self {
+        let secs = |name: &str, default: u64| Duration::from_secs(
+            env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+        );
+        let persist = env::var("MOJANG_CACHE_PERSIST").map(|v| v == "true").unwrap_or(false);
+
+        Self::new(
+            inner,
+            secs("MOJANG_CACHE_TTL_SECS", DEFAULT_TTL_SECS),
+            secs("MOJANG_CACHE_NEGATIVE_TTL_SECS", DEFAULT_NEGATIVE_TTL_SECS),
+            if persist { Some(store) } else { None },
+        )
+    }
+
+    fn ttl_for(&self, lookup: &Lookup) -> Option<Duration> {
+        match lookup {
+            Lookup::Found(_) => Some(self.ttl),
+            Lookup::NotFound => Some(self.negative_ttl),
+            Lookup::RateLimited => None,
+        }
+    }
+
+    fn get_memory(&self, key: &str) -> Option<Lookup> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(key)
+            .filter(|e| e.expires > Instant::now())
+            .map(|e| e.lookup.clone())
+    }
+
+    fn put_memory(&self, key: String, lookup: Lookup, ttl: Duration) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            let now = Instant::now();
+            entries.retain(|_, e| e.expires > now);
+        }
+        entries.insert(key, Entry { lookup, expires: Instant::now() + ttl });
+    }
+}
+
+#[async_trait]
+impl MojangClient for CachedMojangClient {
+
+    async fn lookup_username(&self, username: &str, deadline: Instant) -> Result<Lookup> {
+        let key = username.to_lowercase();
+
+        if let Some(lookup) = self.get_memory(&key) {
+            return Ok(lookup);
+        }
+
+        // A failing cache table should never fail the lookup itself.
+        if let Some(store) = &self.store {
+            match store.get_cached_lookup(&key).await {
+                Ok(Some((lookup, expires_at))) => {
+                    let ttl = (expires_at - Utc::now()).to_std().unwrap_or_default();
+                    self.put_memory(key, lookup.clone(), ttl);
+                    return Ok(lookup);
+                },
+                Ok(None) => {},
+                Err(e) => tracing::warn!("Error reading mojang cache: {:?}", e),
+            }
+        }
+
+        let lookup = self.inner.lookup_username(username, deadline).await?;
+
+        if let Some(ttl) = self.ttl_for(&lookup) {
+            if let Some(store) = &self.store {
+                if let Err(e) = store.put_cached_lookup(&key, &lookup, ttl).await {
+                    tracing::warn!("Error writing mojang cache: {:?}", e);
+                }
+            }
+            self.put_memory(key, lookup.clone(), ttl);
+        }
+
+        Ok(lookup)
+    }
+
+    async fn lookup_uuid(&self, uuid: &str, deadline: Instant) -> Result<Lookup> {
+        self.inner.lookup_uuid(uuid, deadline).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mojang::fake::FakeMojang;
+    use crate::mojang::HttpMojangClient;
+    use crate::mojang::limiter::RateLimiter;
+
+    fn cached(fake: &FakeMojang, ttl: Duration, negative_ttl: Duration) -> CachedMojangClient {
+        let limiter = Arc::new(RateLimiter::new(100, 100.0));
+        let inner = Arc::new(HttpMojangClient::new(&fake.url(), &fake.url(), limiter));
+        CachedMojangClient::new(inner, ttl, negative_ttl, None)
+    }
+
+    /// Leaves no time to retry.
+    fn no_retries() -> Instant {
+        Instant::now()
+    }
+
+    #[tokio::test]
+    async fn found_usernames_are_cached() {
+        let fake = FakeMojang::start().await;
+        fake.add_profile("069a79f444e94726a5befca90e38aaf5", "Notch");
+        let client = cached(&fake, Duration::from_secs(60), Duration::from_secs(60));
+
+        client.lookup_username("Notch", no_retries()).await.unwrap();
+        let lookup = client.lookup_username("notch", no_retries()).await.unwrap();
+
+        assert!(matches!(lookup, Lookup::Found(_)));
+        assert_eq!(fake.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn not_found_usernames_use_the_negative_ttl() {
+        let fake = FakeMojang::start().await;
+        let client = cached(&fake, Duration::from_secs(60), Duration::ZERO);
+
+        client.lookup_username("Nobody", no_retries()).await.unwrap();
+        client.lookup_username("Nobody", no_retries()).await.unwrap();
+
+        assert_eq!(fake.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_lookups_are_not_cached() {
+        let fake = FakeMojang::start().await;
+        fake.respond_with(429);
+        let client = cached(&fake, Duration::from_secs(60), Duration::from_secs(60));
+
+        client.lookup_username("Notch", no_retries()).await.unwrap();
+        client.lookup_username("Notch", no_retries()).await.unwrap();
+
+        assert_eq!(fake.requests(), 2);
+    }
+}
@@ -0,0 +1,142 @@
+//! A tiny in-process stand-in for the mojang api, for pointing an
+//! [`HttpMojangClient`](crate::mojang::HttpMojangClient) at in tests.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use crate::mojang::{is_valid_username, Profile};
+
+#[derive(Default)]
+struct State {
+    profiles: HashMap<String, Profile>,
+    status: Option<u16>,
+    failures: VecDeque<u16>,
+    requests: usize,
+}
+
+#[derive(Clone)]
+pub struct FakeMojang {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeMojang {
+
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind fake mojang");
+        let addr = listener.local_addr().expect("fake mojang addr");
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let _state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, _state.clone()));
+            }
+        });
+
+        FakeMojang { addr, state }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn add_profile(&self, id: &str, name: &str) {
+        let profile = Profile { id: id.to_string(), name: name.to_string() };
+        self.state.lock().unwrap().profiles.insert(name.to_lowercase(), profile);
+    }
+
+    /// Answers every following request with the given status instead.
+    pub fn respond_with(&self, status: u16) {
+        self.state.lock().unwrap().status = Some(status);
+    }
+
+    /// Answers the next `count` requests with the given status, in order with earlier calls.
+    pub fn fail_next(&self, status: u16, count: usize) {
+        let mut state = self.state.lock().unwrap();
+        for _ in 0..count {
+            state.failures.push_back(status);
+        }
+    }
+
+    /// Number of requests served so far.
+    pub fn requests(&self) -> usize {
+        self.state.lock().unwrap().requests
+    }
+}
+
+async fn serve(mut stream: TcpStream, state: Arc<Mutex<State>>) {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    let header_end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let content_length = head.lines()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while buf.len() < header_end + content_length {
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+    let body = &buf[header_end..header_end + content_length];
+
+    let mut parts = head.split_whitespace();
+    let method = parts.next().unwrap_or("GET").to_string();
+    let path = parts.next().unwrap_or("/").to_string();
+
+    let (status, body) = {
+        let mut state = state.lock().unwrap();
+        state.requests += 1;
+        let status = state.failures.pop_front().or(state.status);
+        match (status, method.as_str(), path.as_str()) {
+            (Some(status), _, _) => (status, String::new()),
+            (None, "GET", path) if path.starts_with("/users/profiles/minecraft/") => {
+                let name = path.trim_start_matches("/users/profiles/minecraft/");
+                match state.profiles.get(&name.to_lowercase()) {
+                    Some(p) => (200, serde_json::to_string(p).unwrap()),
+                    None => (404, String::new()),
+                }
+            },
+            (None, "GET", path) if path.starts_with("/session/minecraft/profile/") => {
+                let id = path.trim_start_matches("/session/minecraft/profile/");
+                match state.profiles.values().find(|p| p.id == id) {
+                    Some(p) => (200, serde_json::to_string(p).unwrap()),
+                    None => (204, String::new()),
+                }
+            },
+            (None, "POST", "/profiles/minecraft") => {
+                let names: Vec<String> = serde_json::from_slice(body).unwrap_or_default();
+                // Like mojang, one malformed name fails the whole lookup.
+                if !names.iter().all(|name| is_valid_username(name)) {
+                    (400, String::new())
+                } else {
+                    let found: Vec<&Profile> = names.iter()
+                        .filter_map(|name| state.profiles.get(&name.to_lowercase()))
+                        .collect();
+                    (200, serde_json::to_string(&found).unwrap())
+                }
+            },
+            _ => (404, String::new()),
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 {} Fake\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+use async_trait::async_trait;
+use rand::Rng;
+use reqwest::StatusCode;
+use tokio::time::Instant;
+use crate::mojang::{Lookup, MojangClient, Profile};
+use crate::mojang::limiter::RateLimiter;
+
+const DEFAULT_API_URL: &str = "https://api.mojang.com";
+const DEFAULT_SESSION_URL: &str = "https://sessionserver.mojang.com";
+
+// Mojang allows 600 requests every 10 minutes, the username sync has a share of its own
+const DEFAULT_RATE_LIMIT: u32 = 500;
+const DEFAULT_RATE_LIMIT_WINDOW_SECS: u64 = 10 * 60;
+
+const BASE_BACKOFF_MS: u64 = 50;
+
+// Most usernames the bulk profiles endpoint accepts per request
+pub const MAX_BULK_USERNAMES: usize = 10;
+
+#[derive(Clone, Debug)]
+pub struct HttpMojangClient {
+    client: reqwest::Client,
+    api_url: String,
+    session_url: String,
+    limiter: Arc<RateLimiter>,
+}
+
+impl HttpMojangClient {
+
+    pub fn new(api_url: &str, session_url: &str, limiter: Arc<RateLimiter>) -> Self {
+        HttpMojangClient {
+            client: reqwest::Client::new(),
+            api_url: api_url.trim_end_matches('/').to_string(),
+            session_url: session_url.trim_end_matches('/').to_string(),
+            limiter,
+        }
+    }
+
+    /// Uses MOJANG_API_URL and MOJANG_SESSION_URL if set, otherwise the public mojang apis. Requests are limited to
+    /// MOJANG_RATE_LIMIT per MOJANG_RATE_LIMIT_WINDOW_SECS.
+    pub fn from_env() -> Self {
+        Self::from_env_with_limit("MOJANG_RATE_LIMIT", DEFAULT_RATE_LIMIT)
+    }
+
+    /// [`HttpMojangClient::from_env`] with a limiter of its own, allowing `limit_var` requests per
+    /// MOJANG_RATE_LIMIT_WINDOW_SECS.
+    pub fn from_env_with_limit(limit_var: &str, default_limit: u32) -> Self {
+        let var = |name: &str, default: u64| env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+
+        let api_url = env::var("MOJANG_API_URL").unwrap_or(DEFAULT_API_URL.to_string());
+        let session_url = env::var("MOJANG_SESSION_URL").unwrap_or(DEFAULT_SESSION_URL.to_string());
+        let limiter = RateLimiter::per_window(
+            var(limit_var, default_limit as u64) as u32,
+            Duration::from_secs(var("MOJANG_RATE_LIMIT_WINDOW_SECS", DEFAULT_RATE_LIMIT_WINDOW_SECS)),
+        );
+
+        Self::new(&api_url, &session_url, Arc::new(limiter))
+    }
+
+    /// Sends a request through the rate limiter, retrying 429s, 5xxs and connection errors with
+    /// jittered backoff until the deadline. Returns None if no request could be sent.
+    async fn send(&self, request: reqwest::RequestBuilder, deadline: Instant) -> Result<Option<reqwest::Response>> {
+        let mut attempt = 0;
+
+        loop {
+            if !self.limiter.acquire(deadline).await {
+                return Ok(None);
+            }
+
+            let request = request.try_clone().expect("mojang requests have no streaming body");
+            let result = request.send().await;
+            let retryable = match &result {
+                Ok(response) => response.status() == StatusCode::TOO_MANY_REQUESTS || response.status().is_server_error(),
+                Err(e) => e.is_connect() || e.is_timeout(),
+            };
+
+            let backoff = BASE_BACKOFF_MS << attempt.min(6);
+            let backoff = Duration::from_millis(rand::thread_rng().gen_range(0..=backoff));
+            if !retryable || Instant::now() + backoff > deadline {
+                return Ok(Some(result?));
+            }
+
+            tracing::debug!("Retrying mojang request in {:?}", backoff);
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[async_trait]
+impl MojangClient for HttpMojangClient {
+
+    async fn lookup_username(&self, username: &str, deadline: Instant) -> Result<Lookup> {
+        let url = format!("{}/users/profiles/minecraft/{}", self.api_url, username);
+        let response = match self.send(self.client.get(&url), deadline).await? {
+            Some(response) => response,
+            None => return Ok(Lookup::RateLimited),
+        };
+
+        match response.status() {
+            StatusCode::OK => Ok(Lookup::Found(response.json::<Profile>().await?)),
+            StatusCode::NOT_FOUND => Ok(Lookup::NotFound),
+            StatusCode::TOO_MANY_REQUESTS => Ok(Lookup::RateLimited),
+            status => Err(anyhow::anyhow!("Unexpected status {} looking up {}", status, username)),
+        }
+    }
+
+    async fn lookup_uuid(&self, uuid: &str, deadline: Instant) -> Result<Lookup> {
+        let url = format!("{}/session/minecraft/profile/{}", self.session_url, uuid.replace('-', ""));
+        let response = match self.send(self.client.get(&url), deadline).await? {
+            Some(response) => response,
+            None => return Ok(Lookup::RateLimited),
+        };
+
+        match response.status() {
+            StatusCode::OK => Ok(Lookup::Found(response.json::<Profile>().await?)),
+            StatusCode::NO_CONTENT | StatusCode::NOT_FOUND => Ok(Lookup::NotFound),
+            StatusCode::TOO_MANY_REQUESTS => Ok(Lookup::RateLimited),
+            status => Err(anyhow::anyhow!("Unexpected status {} looking up {}", status, uuid)),
+        }
+    }
+
+    async fn lookup_usernames(&self, usernames: &[String], deadline: Instant) -> Result<Vec<Lookup>> {
+        let url = format!("{}/profiles/minecraft", self.api_url);
+        let mut re = Vec::new();
+
+        for chunk in usernames.chunks(MAX_BULK_USERNAMES) {
+            let response = match self.send(self.client.post(&url).json(chunk), deadline).await? {
+                Some(response) => response,
+                None => {
+                    re.extend(chunk.iter().map(|_| Lookup::RateLimited));
+                    continue;
+                },
+            };
+
+            match response.status() {
+                StatusCode::OK => {
+                    // Unknown usernames are left out of the response.
+                    let mut found: HashMap<String, Profile> = response.json::<Vec<Profile>>().await?
+                        .into_iter()
+                        .map(|p| (p.name.to_lowercase(), p))
+                        .collect();
+                    re.extend(chunk.iter().map(|name| match found.remove(&name.to_lowercase()) {
+                        Some(profile) => Lookup::Found(profile),
+                        None => Lookup::NotFound,
+                    }));
+                },
+                StatusCode::TOO_MANY_REQUESTS => re.extend(chunk.iter().map(|_| Lookup::RateLimited)),
+                status => return Err(anyhow::anyhow!("Unexpected status {} looking up {} usernames", status, chunk.len())),
+            }
+        }
+
+        Ok(re)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mojang::fake::FakeMojang;
+
+    fn client(fake: &FakeMojang) -> HttpMojangClient {
+        let limiter = Arc::new(RateLimiter::new(100, 100.0));
+        HttpMojangClient::new(&fake.url(), &fake.url(), limiter)
+    }
+
+    fn soon() -> Instant {
+        Instant::now() + Duration::from_millis(200)
+    }
+
+    #[tokio::test]
+    async fn finds_known_username() {
+        let fake = FakeMojang::start().await;
+        fake.add_profile("069a79f444e94726a5befca90e38aaf5", "Notch");
+        let client = client(&fake);
+
+        let lookup = client.lookup_username("Notch", soon()).await.unwrap();
+
+        assert_eq!(lookup, Lookup::Found(Profile {
+            id: "069a79f444e94726a5befca90e38aaf5".to_string(),
+            name: "Notch".to_string(),
+        }));
+    }
+
+    #[tokio::test]
+    async fn unknown_username_is_not_found() {
+        let fake = FakeMojang::start().await;
+        let client = client(&fake);
+
+        assert_eq!(client.lookup_username("Nobody", soon()).await.unwrap(), Lookup::NotFound);
+    }
+
+    #[tokio::test]
+    async fn too_many_requests_is_rate_limited() {
+        let fake = FakeMojang::start().await;
+        fake.respond_with(429);
+        let client = client(&fake);
+
+        assert_eq!(client.lookup_username("Notch", soon()).await.unwrap(), Lookup::RateLimited);
+    }
+
+    #[tokio::test]
+    async fn server_error_is_an_error() {
+        let fake = FakeMojang::start().await;
+        fake.respond_with(500);
+        let client = client(&fake);
+
+        assert!(client.lookup_username("Notch", soon()).await.is_err());
+        assert!(fake.requests() > 1);
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried() {
+        let fake = FakeMojang::start().await;
+        fake.add_profile("069a79f444e94726a5befca90e38aaf5", "Notch");
+        fake.fail_next(429, 1);
+        fake.fail_next(503, 1);
+        let client = client(&fake);
+
+        assert!(matches!(client.lookup_username("Notch", soon()).await.unwrap(), Lookup::Found(_)));
+        assert_eq!(fake.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn retries_end_at_the_deadline() {
+        let fake = FakeMojang::start().await;
+        fake.respond_with(503);
+        let client = client(&fake);
+
+        let started = Instant::now();
+        assert!(client.lookup_username("Notch", Instant::now() + Duration::from_millis(100)).await.is_err());
+        assert!(started.elapsed() < Duration::from_millis(300));
+        assert!(fake.requests() > 1);
+    }
+
+    #[tokio::test]
+    async fn finds_current_name_by_uuid() {
+        let fake = FakeMojang::start().await;
+        fake.add_profile("069a79f444e94726a5befca90e38aaf5", "Notch");
+        let client = client(&fake);
+
+        let lookup = client.lookup_uuid("069a79f4-44e9-4726-a5be-fca90e38aaf5", soon()).await.unwrap();
+
+        assert!(matches!(lookup, Lookup::Found(p) if p.name == "Notch"));
+        assert_eq!(client.lookup_uuid("853c80ef3c3749fdaa49938b674adae6", soon()).await.unwrap(), Lookup::NotFound);
+    }
+
+    #[tokio::test]
+    async fn bulk_lookup_keeps_request_order() {
+        let fake = FakeMojang::start().await;
+        fake.add_profile("069a79f444e94726a5befca90e38aaf5", "Notch");
+        fake.add_profile("853c80ef3c3749fdaa49938b674adae6", "jeb_");
+        let client = client(&fake);
+
+        let names = vec!["JEB_".to_string(), "Nobody".to_string(), "Notch".to_string()];
+        let lookups = client.lookup_usernames(&names, soon()).await.unwrap();
+
+        assert!(matches!(&lookups[0], Lookup::Found(p) if p.name == "jeb_"));
+        assert_eq!(lookups[1], Lookup::NotFound);
+        assert!(matches!(&lookups[2], Lookup::Found(p) if p.name == "Notch"));
+        assert_eq!(fake.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn exhausted_limiter_is_rate_limited() {
+        let fake = FakeMojang::start().await;
+        fake.add_profile("069a79f444e94726a5befca90e38aaf5", "Notch");
+        let limiter = Arc::new(RateLimiter::new(1, 0.1));
+        let client = HttpMojangClient::new(&fake.url(), &fake.url(), limiter);
+
+        assert!(matches!(client.lookup_username("Notch", soon()).await.unwrap(), Lookup::Found(_)));
+        assert_eq!(client.lookup_username("Notch", soon()).await.unwrap(), Lookup::RateLimited);
+        assert_eq!(fake.requests(), 1);
+    }
+}
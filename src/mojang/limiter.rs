@@ -0,0 +1,88 @@
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Token bucket shared by every in-flight mojang request.
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+
+    pub fn new(capacity: u32, per_sec: f64) -> Self {
+        RateLimiter {
+            capacity: capacity as f64,
+            per_sec,
+            bucket: Mutex::new(Bucket { tokens: capacity as f64, refilled: Instant::now() }),
+        }
+    }
+
+    /// Allows at most `limit` requests in any `window`, the burst plus
+    /// everything refilled during a window never adds up to more than that.
+    pub fn per_window(limit: u32, window: Duration) -> Self {
+        let burst = (limit / 10).max(1);
+        let per_sec = limit.saturating_sub(burst).max(1) as f64 / window.as_secs_f64();
+        Self::new(burst, per_sec)
+    }
+
+    /// Waits for a token, giving up if one won't be available before the deadline.
+    pub async fn acquire(&self, deadline: Instant) -> bool {
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.per_sec).min(self.capacity);
+            bucket.refilled = now;
+
+            let wait = if bucket.tokens >= 1.0 {
+                Duration::ZERO
+            } else {
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_sec)
+            };
+            if !wait.is_zero() && now + wait > deadline {
+                return false;
+            }
+
+            // Reserve the token now so waiters queue up behind each other.
+            bucket.tokens -= 1.0;
+            wait
+        };
+
+        tokio::time::sleep(wait).await;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn gives_up_when_the_deadline_is_too_close() {
+        let limiter = RateLimiter::new(1, 1.0);
+        let deadline = Instant::now() + Duration::from_millis(100);
+
+        assert!(limiter.acquire(deadline).await);
+        assert!(!limiter.acquire(deadline).await);
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_refill() {
+        let limiter = RateLimiter::new(1, 20.0);
+        let deadline = Instant::now() + Duration::from_secs(1);
+
+        assert!(limiter.acquire(deadline).await);
+        let start = Instant::now();
+        assert!(limiter.acquire(deadline).await);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+}
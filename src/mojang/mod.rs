@@ -0,0 +1,50 @@
+mod batch;
+mod cache;
+mod http;
+pub mod limiter;
+#[cfg(test)]
+pub mod fake;
+
+use std::fmt::Debug;
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+pub use batch::BatchingMojangClient;
+pub use cache::CachedMojangClient;
+pub use http::HttpMojangClient;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Profile {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Lookup {
+    Found(Profile),
+    NotFound,
+    RateLimited,
+}
+
+/// Whether the name could be a minecraft username, 1 to 16 letters, digits and underscores.
+/// Mojang rejects a whole bulk lookup if one of the names isn't.
+pub fn is_valid_username(name: &str) -> bool {
+    (1..=16).contains(&name.len()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Resolves minecraft usernames to profiles. Lookups wait for the rate limit and retry
+/// until the deadline, and are rate limited if no request could be sent before it.
+#[async_trait]
+pub trait MojangClient: Debug + Send + Sync {
+    async fn lookup_username(&self, username: &str, deadline: Instant) -> Result<Lookup>;
+
+    /// Looks up the current profile for a minecraft uuid.
+    async fn lookup_uuid(&self, uuid: &str, deadline: Instant) -> Result<Lookup>;
+
+    /// Looks up several usernames at once, returning a lookup for each in the same order.
+    async fn lookup_usernames(&self, usernames: &[String], deadline: Instant) -> Result<Vec<Lookup>> {
+        futures::future::try_join_all(usernames.iter().map(|u| self.lookup_username(u, deadline))).await
+    }
+}
@@ -0,0 +1,727 @@
+//! An in-memory [`AccountStore`] for tests, it keeps the same rules as the postgres tables:
+//! usernames are unique, every owner has exactly one main account, and whitelist changes and
+//! change events for an account are handed out oldest first. Accounts added with [`MemoryStore::add_legacy_account`]
+//! have no user id, like rows from before user ids were enforced, so the backfill can be tested.
+
+use std::sync::Mutex;
+use std::time::Duration;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use protobuf::{Message, SpecialFields};
+use sqlx::types::Uuid;
+use tokio::sync::Notify;
+use crate::events;
+use crate::proto::minecraft_account::MinecraftAccount;
+use crate::proto::minecraft_account_update::MinecraftAccountChangeType;
+use crate::store::{AccountOwner, AccountStore, AddResult, LegacyAccount, DeleteResult, EventEntry, OutboxEntry, StoredAccount, UsernameHistoryEntry, DEPRECATED_FIRST_NAME, WHITELIST_HANDOFF};
+use crate::whitelist::WhitelistAction;
+
+#[derive(Debug, Clone)]
+struct Row {
+    id: i64,
+    discord_id: Option<String>,
+    /// None for legacy rows only, which are owned by their discord id until backfilled.
+    user_id: Option<String>,
+    minecraft_uuid: Uuid,
+    minecraft_username: String,
+    is_main: bool,
+}
+
+impl Row {
+    fn owner(&self) -> Option<String> {
+        self.user_id.clone().or(self.discord_id.clone())
+    }
+
+    fn account(&self) -> MinecraftAccount {
+        MinecraftAccount {
+            deprecated_first_name: DEPRECATED_FIRST_NAME.to_string(),
+            minecraft_uuid: self.minecraft_uuid.to_string(),
+            minecraft_username: self.minecraft_username.clone(),
+            is_main: self.is_main,
+            special_fields: SpecialFields::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HistoryRow {
+    minecraft_uuid: Uuid,
+    minecraft_username: String,
+    valid_from: DateTime<Utc>,
+    valid_to: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+struct OutboxRow {
+    id: i64,
+    minecraft_uuid: Uuid,
+    action: WhitelistAction,
+    attempts: i32,
+    next_attempt_at: DateTime<Utc>,
+    last_error: Option<String>,
+    delivered_at: Option<DateTime<Utc>>,
+}
+
+impl OutboxRow {
+    fn entry(&self) -> OutboxEntry {
+        OutboxEntry {
+            id: self.id,
+            minecraft_uuid: self.minecraft_uuid.to_string(),
+            action: self.action,
+            attempts: self.attempts,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct EventRow {
+    id: i64,
+    minecraft_uuid: Uuid,
+    payload: Vec<u8>,
+    attempts: i32,
+    next_attempt_at: DateTime<Utc>,
+    last_error: Option<String>,
+    published_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    accounts: Vec<Row>,
+    history: Vec<HistoryRow>,
+    outbox: Vec<OutboxRow>,
+    events: Vec<EventRow>,
+    next_id: i64,
+}
+
+impl State {
+
+    fn next_id(&mut self) -> i64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn find(&self, uuid: Uuid) -> Option<usize> {
+        self.accounts.iter().position(|r| r.minecraft_uuid == uuid)
+    }
+
+    /// The checks the accounts table's unique indexes make.
+    fn check_unique(&self, row: &Row) -> Result<()> {
+        let others = || self.accounts.iter().filter(|r| r.id != row.id);
+        if others().any(|r| r.minecraft_username == row.minecraft_username) {
+            anyhow::bail!("duplicate key value violates unique constraint \"accounts_minecraft_username_key\"");
+        }
+        if row.is_main && others().any(|r| r.is_main && r.owner() == row.owner()) {
+            anyhow::bail!("duplicate key value violates unique constraint \"accounts_one_main\"");
+        }
+        Ok(())
+    }
+
+    fn record_username(&mut self, uuid: Uuid, username: &str) {
+        let now = Utc::now();
+        for h in self.history.iter_mut() {
+            if h.minecraft_uuid == uuid && h.valid_to.is_none() && h.minecraft_username != username {
+                h.valid_to = Some(now);
+            }
+        }
+        if !self.history.iter().any(|h| h.minecraft_uuid == uuid && h.valid_to.is_none()) {
+            self.history.push(HistoryRow {
+                minecraft_uuid: uuid,
+                minecraft_username: username.to_string(),
+                valid_from: now,
+                valid_to: None,
+            });
+        }
+    }
+
+    fn close_username_history(&mut self, uuid: Uuid) {
+        let now = Utc::now();
+        for h in self.history.iter_mut() {
+            if h.minecraft_uuid == uuid && h.valid_to.is_none() {
+                h.valid_to = Some(now);
+            }
+        }
+    }
+
+    fn enqueue_whitelist(&mut self, uuid: Uuid, action: WhitelistAction) -> i64 {
+        let id = self.next_id();
+        self.outbox.push(OutboxRow {
+            id,
+            minecraft_uuid: uuid,
+            action,
+            attempts: 0,
+            next_attempt_at: Utc::now() + WHITELIST_HANDOFF,
+            last_error: None,
+            delivered_at: None,
+        });
+        id
+    }
+
+    /// Whether no older change for the same account is still pending.
+    fn is_next_for_account(&self, row: &OutboxRow) -> bool {
+        !self.outbox.iter().any(|p| p.minecraft_uuid == row.minecraft_uuid && p.delivered_at.is_none() && p.id < row.id)
+    }
+
+    fn enqueue_event(&mut self, row: &Row, change: MinecraftAccountChangeType) {
+        let id = self.next_id();
+        let event = events::change(row.user_id.clone(), row.discord_id.clone(), change, row.account());
+        self.events.push(EventRow {
+            id,
+            minecraft_uuid: row.minecraft_uuid,
+            payload: event.write_to_bytes().unwrap(),
+            attempts: 0,
+            next_attempt_at: Utc::now(),
+            last_error: None,
+            published_at: None,
+        });
+    }
+
+    /// Whether no older event for the same account is still unpublished.
+    fn is_next_event_for_account(&self, row: &EventRow) -> bool {
+        !self.events.iter().any(|p| p.minecraft_uuid == row.minecraft_uuid && p.published_at.is_none() && p.id < row.id)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+    outbox: Notify,
+    events: Notify,
+}
+
+impl MemoryStore {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an account that only has a discord id, as if it was added before user ids were enforced.
+    pub fn add_legacy_account(&self, discord_id: &str, account: &MinecraftAccount) {
+        let mut state = self.state.lock().unwrap();
+        let mut row = Row {
+            id: state.next_id(),
+            discord_id: Some(discord_id.to_string()),
+            user_id: None,
+            minecraft_uuid: Uuid::parse_str(&account.minecraft_uuid).unwrap(),
+            minecraft_username: account.minecraft_username.clone(),
+            is_main: false,
+        };
+        row.is_main = !state.accounts.iter().any(|r| r.is_main && r.owner() == row.owner());
+        state.accounts.push(row);
+    }
+
+    /// Every queued whitelist change as (uuid, attempts, last error, delivered), oldest first.
+    pub fn whitelist_outbox(&self) -> Vec<(String, i32, Option<String>, bool)> {
+        let state = self.state.lock().unwrap();
+        state.outbox.iter()
+            .map(|o| (o.minecraft_uuid.to_string(), o.attempts, o.last_error.clone(), o.delivered_at.is_some()))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl AccountStore for MemoryStore {
+
+    async fn add_account(&self, user_id: &str, discord_id: Option<String>, account: &MinecraftAccount) -> Result<AddResult> {
+        let uuid = Uuid::parse_str(&account.minecraft_uuid)?;
+        let mut state = self.state.lock().unwrap();
+
+        let mut row = Row {
+            id: 0,
+            discord_id,
+            user_id: Some(user_id.to_string()),
+            minecraft_uuid: uuid,
+            minecraft_username: account.minecraft_username.clone(),
+            is_main: false,
+        };
+        row.is_main = !state.accounts.iter().any(|r| r.is_main && r.owner() == row.owner());
+        state.check_unique(&row)?;
+
+        row.id = state.next_id();
+        state.accounts.push(row.clone());
+        state.record_username(uuid, &row.minecraft_username);
+        let outbox_id = state.enqueue_whitelist(uuid, WhitelistAction::Add);
+        state.enqueue_event(&row, MinecraftAccountChangeType::ADDED);
+        self.events.notify_one();
+
+        Ok(AddResult { account: row.account(), outbox_id })
+    }
+
+    async fn rename_account(&self, minecraft_uuid: &str, username: &str) -> Result<MinecraftAccount> {
+        let uuid = Uuid::parse_str(minecraft_uuid)?;
+        let mut state = self.state.lock().unwrap();
+
+        let i = state.find(uuid).ok_or(sqlx::Error::RowNotFound)?;
+        let mut row = state.accounts[i].clone();
+        row.minecraft_username = username.to_string();
+        state.check_unique(&row)?;
+
+        state.accounts[i] = row.clone();
+        state.record_username(uuid, &row.minecraft_username);
+        state.enqueue_event(&row, MinecraftAccountChangeType::UPDATED);
+        self.events.notify_one();
+
+        Ok(row.account())
+    }
+
+    async fn delete_account(&self, minecraft_uuid: &str) -> Result<DeleteResult> {
+        let uuid = Uuid::parse_str(minecraft_uuid)?;
+        let mut state = self.state.lock().unwrap();
+
+        let deleted = match state.find(uuid) {
+            None => return Ok(DeleteResult { deleted: false, outbox_id: None }),
+            // Like postgres, which can't read the NULL user_id back.
+            Some(i) if state.accounts[i].user_id.is_none() => {
+                anyhow::bail!("{} has no user id, delete it as a legacy account", uuid);
+            },
+            Some(i) => state.accounts.remove(i),
+        };
+
+        state.close_username_history(uuid);
+        let outbox_id = state.enqueue_whitelist(uuid, WhitelistAction::Remove);
+        state.enqueue_event(&deleted, MinecraftAccountChangeType::REMOVED);
+
+        if deleted.is_main {
+            let oldest = state.accounts.iter_mut()
+                .filter(|r| r.owner() == deleted.owner())
+                .min_by_key(|r| r.id);
+            if let Some(row) = oldest {
+                row.is_main = true;
+                let row = row.clone();
+                state.enqueue_event(&row, MinecraftAccountChangeType::UPDATED);
+            }
+        }
+        self.events.notify_one();
+
+        Ok(DeleteResult { deleted: true, outbox_id: Some(outbox_id) })
+    }
+
+    async fn set_main(&self, minecraft_uuid: &str) -> Result<MinecraftAccount> {
+        let uuid = Uuid::parse_str(minecraft_uuid)?;
+        let mut state = self.state.lock().unwrap();
+
+        let i = state.find(uuid).ok_or(sqlx::Error::RowNotFound)?;
+        let owner = state.accounts[i].owner();
+
+        let mut previous = None;
+        for row in state.accounts.iter_mut() {
+            if row.is_main && row.minecraft_uuid != uuid && row.owner() == owner {
+                row.is_main = false;
+                previous = Some(row.clone());
+            }
+        }
+        state.accounts[i].is_main = true;
+
+        // Nothing changed if it was already the main account.
+        if let Some(previous) = &previous {
+            let row = state.accounts[i].clone();
+            state.enqueue_event(previous, MinecraftAccountChangeType::UPDATED);
+            state.enqueue_event(&row, MinecraftAccountChangeType::UPDATED);
+            self.events.notify_one();
+        }
+
+        Ok(state.accounts[i].account())
+    }
+
+    async fn minecraft_name_to_uuid(&self, name: &str) -> Result<Option<String>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.accounts.iter()
+            .find(|r| r.minecraft_username == name)
+            .map(|r| r.minecraft_uuid.to_string()))
+    }
+
+    async fn uuid_owner(&self, minecraft_uuid: &str) -> Result<Option<AccountOwner>> {
+        let uuid = Uuid::parse_str(minecraft_uuid)?;
+        let state = self.state.lock().unwrap();
+        Ok(state.find(uuid).map(|i| AccountOwner {
+            user_id: state.accounts[i].user_id.clone().unwrap_or_default(),
+            discord_id: state.accounts[i].discord_id.clone(),
+        }))
+    }
+
+    async fn get_by_user(&self, id: &str) -> Result<Vec<MinecraftAccount>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.accounts.iter()
+            .filter(|r| r.user_id.as_deref() == Some(id))
+            .map(|r| r.account())
+            .collect())
+    }
+
+    async fn get_by_minecraft(&self, uuid: &str) -> Result<Option<MinecraftAccount>> {
+        let uuid = Uuid::parse_str(uuid)?;
+        let state = self.state.lock().unwrap();
+        Ok(state.find(uuid).map(|i| state.accounts[i].account()))
+    }
+
+    async fn get_page(&self, after_id: i64, limit: i64) -> Result<Vec<StoredAccount>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.accounts.iter()
+            .filter(|r| r.id > after_id)
+            .take(limit.max(0) as usize)
+            .map(|r| StoredAccount { id: r.id, account: r.account() })
+            .collect())
+    }
+
+    async fn discord_only_owners(&self) -> Result<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        let mut ids: Vec<String> = state.accounts.iter()
+            .filter(|r| r.user_id.is_none())
+            .filter_map(|r| r.discord_id.clone())
+            .collect();
+        ids.sort();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    async fn accounts_without_user_id(&self) -> Result<Vec<LegacyAccount>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.accounts.iter()
+            .filter(|r| r.user_id.is_none())
+            .map(|r| LegacyAccount { discord_id: r.discord_id.clone(), account: r.account() })
+            .collect())
+    }
+
+    async fn delete_legacy_account(&self, minecraft_uuid: &str) -> Result<bool> {
+        let uuid = Uuid::parse_str(minecraft_uuid)?;
+        let mut state = self.state.lock().unwrap();
+
+        let deleted = match state.find(uuid) {
+            Some(i) if state.accounts[i].user_id.is_none() => state.accounts.remove(i),
+            _ => return Ok(false),
+        };
+
+        state.close_username_history(uuid);
+        state.enqueue_whitelist(uuid, WhitelistAction::Remove);
+        state.enqueue_event(&deleted, MinecraftAccountChangeType::REMOVED);
+        self.events.notify_one();
+        Ok(true)
+    }
+
+    async fn assign_user_id(&self, discord_id: &str, user_id: &str) -> Result<Vec<MinecraftAccount>> {
+        let mut state = self.state.lock().unwrap();
+        let has_main = state.accounts.iter().any(|r| r.is_main && r.user_id.as_deref() == Some(user_id));
+
+        let mut changed = Vec::new();
+        for row in state.accounts.iter_mut() {
+            if row.user_id.is_none() && row.discord_id.as_deref() == Some(discord_id) {
+                row.user_id = Some(user_id.to_string());
+                row.is_main = row.is_main && !has_main;
+                changed.push(row.clone());
+            }
+        }
+        for row in &changed {
+            state.enqueue_event(row, MinecraftAccountChangeType::UPDATED);
+        }
+        self.events.notify_one();
+        Ok(changed.iter().map(|r| r.account()).collect())
+    }
+
+    async fn republish_page(&self, after_id: i64, limit: i64) -> Result<Option<i64>> {
+        let mut state = self.state.lock().unwrap();
+        let page: Vec<Row> = state.accounts.iter()
+            .filter(|r| r.id > after_id)
+            .take(limit.max(0) as usize)
+            .cloned()
+            .collect();
+        for row in &page {
+            state.enqueue_event(row, MinecraftAccountChangeType::UPDATED);
+        }
+        self.events.notify_one();
+        Ok(page.last().map(|r| r.id))
+    }
+
+    async fn username_history(&self, username: &str) -> Result<Vec<UsernameHistoryEntry>> {
+        let state = self.state.lock().unwrap();
+        let mut entries: Vec<UsernameHistoryEntry> = state.history.iter()
+            .filter(|h| h.minecraft_username.to_lowercase() == username.to_lowercase())
+            .map(|h| UsernameHistoryEntry {
+                minecraft_uuid: h.minecraft_uuid.to_string(),
+                minecraft_username: h.minecraft_username.clone(),
+                valid_from: h.valid_from,
+                valid_to: h.valid_to,
+            })
+            .collect();
+        entries.sort_by_key(|e| std::cmp::Reverse(e.valid_from));
+        Ok(entries)
+    }
+
+    async fn outbox_notified(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.outbox.notified()).await;
+    }
+
+    async fn claim_whitelist(&self, limit: i64, lease: Duration) -> Result<Vec<OutboxEntry>> {
+        let mut state = self.state.lock().unwrap();
+        let now = Utc::now();
+
+        let due: Vec<i64> = state.outbox.iter()
+            .filter(|o| o.delivered_at.is_none() && o.next_attempt_at <= now && state.is_next_for_account(o))
+            .take(limit.max(0) as usize)
+            .map(|o| o.id)
+            .collect();
+
+        let mut entries = Vec::new();
+        for row in state.outbox.iter_mut().filter(|o| due.contains(&o.id)) {
+            row.next_attempt_at = now + lease;
+            entries.push(row.entry());
+        }
+        Ok(entries)
+    }
+
+    async fn claim_whitelist_entry(&self, id: i64) -> Result<Option<OutboxEntry>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.outbox.iter()
+            .find(|o| o.id == id && o.delivered_at.is_none() && state.is_next_for_account(o))
+            .map(|o| o.entry()))
+    }
+
+    async fn release_whitelist(&self, id: i64) -> Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(row) = state.outbox.iter_mut().find(|o| o.id == id) {
+                row.next_attempt_at = Utc::now();
+            }
+        }
+        self.outbox.notify_one();
+        Ok(())
+    }
+
+    async fn whitelist_delivered(&self, id: i64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(row) = state.outbox.iter_mut().find(|o| o.id == id) {
+            row.delivered_at = Some(Utc::now());
+        }
+        Ok(())
+    }
+
+    async fn whitelist_failed(&self, id: i64, error: &str, retry_in: Duration) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(row) = state.outbox.iter_mut().find(|o| o.id == id) {
+            row.attempts += 1;
+            row.last_error = Some(error.to_string());
+            row.next_attempt_at = Utc::now() + retry_in;
+        }
+        Ok(())
+    }
+
+    async fn events_notified(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.events.notified()).await;
+    }
+
+    async fn claim_events(&self, limit: i64, lease: Duration) -> Result<Vec<EventEntry>> {
+        let mut state = self.state.lock().unwrap();
+        let now = Utc::now();
+
+        let due: Vec<i64> = state.events.iter()
+            .filter(|e| e.published_at.is_none() && e.next_attempt_at <= now && state.is_next_event_for_account(e))
+            .take(limit.max(0) as usize)
+            .map(|e| e.id)
+            .collect();
+
+        let mut entries = Vec::new();
+        for row in state.events.iter_mut().filter(|e| due.contains(&e.id)) {
+            row.next_attempt_at = now + lease;
+            entries.push(EventEntry { id: row.id, payload: row.payload.clone(), attempts: row.attempts });
+        }
+        Ok(entries)
+    }
+
+    async fn event_published(&self, id: i64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(row) = state.events.iter_mut().find(|e| e.id == id) {
+            row.published_at = Some(Utc::now());
+        }
+        Ok(())
+    }
+
+    async fn event_failed(&self, id: i64, error: &str, retry_in: Duration) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(row) = state.events.iter_mut().find(|e| e.id == id) {
+            row.attempts += 1;
+            row.last_error = Some(error.to_string());
+            row.next_attempt_at = Utc::now() + retry_in;
+        }
+        Ok(())
+    }
+
+    async fn all_uuids(&self) -> Result<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.accounts.iter().map(|r| r.minecraft_uuid.to_string()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::minecraft_account_update::MinecraftAccountChanged;
+
+    const NOTCH: &str = "069a79f4-44e9-4726-a5be-fca90e38aaf5";
+    const JEB: &str = "853c80ef-3c37-49fd-aa49-938b674adae6";
+    const DINNERBONE: &str = "61699b2e-d327-4a01-9f1e-0ea8c3f06bc6";
+
+    fn account(uuid: &str, username: &str) -> MinecraftAccount {
+        let mut account = MinecraftAccount::new();
+        account.minecraft_uuid = uuid.to_string();
+        account.minecraft_username = username.to_string();
+        account
+    }
+
+    #[tokio::test]
+    async fn only_the_first_account_becomes_main() {
+        let store = MemoryStore::new();
+
+        let first = store.add_account("user", None, &account(NOTCH, "Notch")).await.unwrap();
+        let second = store.add_account("user", None, &account(JEB, "jeb_")).await.unwrap();
+
+        assert!(first.account.is_main);
+        assert!(!second.account.is_main);
+        assert_eq!(store.get_by_user("user").await.unwrap().iter().filter(|a| a.is_main).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn renaming_keeps_a_concurrent_main_change() {
+        let store = MemoryStore::new();
+        store.add_account("user", None, &account(NOTCH, "Notch")).await.unwrap();
+        store.add_account("user", None, &account(JEB, "jeb_")).await.unwrap();
+
+        // The sync job read JEB as not main, then the owner made it main.
+        store.set_main(JEB).await.unwrap();
+        let renamed = store.rename_account(JEB, "jeb").await.unwrap();
+
+        assert!(renamed.is_main);
+        assert_eq!(renamed.minecraft_username, "jeb");
+    }
+
+    #[tokio::test]
+    async fn usernames_are_unique() {
+        let store = MemoryStore::new();
+
+        store.add_account("user", None, &account(NOTCH, "Notch")).await.unwrap();
+
+        assert!(store.add_account("other", None, &account(JEB, "Notch")).await.is_err());
+        assert_eq!(store.all_uuids().await.unwrap(), vec![NOTCH.to_string()]);
+    }
+
+    #[tokio::test]
+    async fn deleting_an_account_ends_its_name() {
+        let store = MemoryStore::new();
+        store.add_account("user", None, &account(NOTCH, "Notch")).await.unwrap();
+
+        store.delete_account(NOTCH).await.unwrap();
+
+        let history = store.username_history("Notch").await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert!(history[0].valid_to.is_some());
+    }
+
+    #[tokio::test]
+    async fn uuid_owner_names_user_and_discord_id() {
+        let store = MemoryStore::new();
+        store.add_account("user", Some("discord".into()), &account(NOTCH, "Notch")).await.unwrap();
+
+        let owner = store.uuid_owner(NOTCH).await.unwrap().unwrap();
+        assert_eq!(owner.user_id, "user");
+        assert_eq!(owner.discord_id.as_deref(), Some("discord"));
+        assert!(store.uuid_owner(JEB).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn assigning_a_user_id_keeps_the_users_main_account() {
+        let store = MemoryStore::new();
+        store.add_account("user", None, &account(NOTCH, "Notch")).await.unwrap();
+        store.add_legacy_account("discord", &account(JEB, "jeb_"));
+        store.add_legacy_account("discord", &account(DINNERBONE, "Dinnerbone"));
+        assert_eq!(store.discord_only_owners().await.unwrap(), vec!["discord".to_string()]);
+
+        let changed = store.assign_user_id("discord", "user").await.unwrap();
+
+        assert_eq!(changed.len(), 2);
+        assert!(changed.iter().all(|a| !a.is_main));
+        assert_eq!(store.get_by_user("user").await.unwrap().iter().filter(|a| a.is_main).count(), 1);
+        assert!(store.discord_only_owners().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn deleting_the_main_account_promotes_the_oldest() {
+        let store = MemoryStore::new();
+        store.add_account("user", None, &account(NOTCH, "Notch")).await.unwrap();
+        store.add_account("user", None, &account(JEB, "jeb_")).await.unwrap();
+        store.add_account("user", None, &account(DINNERBONE, "Dinnerbone")).await.unwrap();
+
+        let deleted = store.delete_account(NOTCH).await.unwrap();
+
+        assert!(deleted.deleted);
+        assert!(store.get_by_minecraft(JEB).await.unwrap().unwrap().is_main);
+        assert!(!store.delete_account(NOTCH).await.unwrap().deleted);
+    }
+
+    #[tokio::test]
+    async fn whitelist_changes_are_claimed_in_order_per_account() {
+        let store = MemoryStore::new();
+        let added = store.add_account("user", None, &account(NOTCH, "Notch")).await.unwrap();
+        let removed = store.delete_account(NOTCH).await.unwrap().outbox_id.unwrap();
+
+        assert!(store.claim_whitelist_entry(removed).await.unwrap().is_none());
+        assert!(store.claim_whitelist(10, Duration::from_secs(30)).await.unwrap().is_empty());
+
+        store.release_whitelist(added.outbox_id).await.unwrap();
+        let claimed = store.claim_whitelist(10, Duration::from_secs(30)).await.unwrap();
+        assert_eq!(claimed.iter().map(|e| e.id).collect::<Vec<_>>(), vec![added.outbox_id]);
+
+        store.whitelist_delivered(added.outbox_id).await.unwrap();
+        assert_eq!(store.claim_whitelist_entry(removed).await.unwrap().unwrap().action, WhitelistAction::Remove);
+    }
+
+    #[tokio::test]
+    async fn change_events_are_claimed_in_order_per_account() {
+        let store = MemoryStore::new();
+        store.add_account("user", None, &account(NOTCH, "Notch")).await.unwrap();
+        store.add_account("user", None, &account(JEB, "jeb_")).await.unwrap();
+        store.set_main(JEB).await.unwrap();
+
+        let changes = |claimed: &[EventEntry]| claimed.iter()
+            .map(|e| MinecraftAccountChanged::parse_from_bytes(&e.payload).unwrap())
+            .map(|c| (c.account.minecraft_uuid.clone(), c.change.enum_value().unwrap()))
+            .collect::<Vec<_>>();
+
+        // The main account swap waits until both accounts were announced.
+        let claimed = store.claim_events(10, Duration::from_secs(30)).await.unwrap();
+        assert_eq!(changes(&claimed), vec![
+            (NOTCH.to_string(), MinecraftAccountChangeType::ADDED),
+            (JEB.to_string(), MinecraftAccountChangeType::ADDED),
+        ]);
+        assert!(claimed.iter().all(|e| MinecraftAccountChanged::parse_from_bytes(&e.payload).unwrap().user_id.as_deref() == Some("user")));
+
+        store.event_failed(claimed[0].id, "stream unavailable", Duration::ZERO).await.unwrap();
+        store.event_published(claimed[1].id).await.unwrap();
+
+        let claimed = store.claim_events(10, Duration::from_secs(30)).await.unwrap();
+        assert_eq!(changes(&claimed), vec![
+            (NOTCH.to_string(), MinecraftAccountChangeType::ADDED),
+            (JEB.to_string(), MinecraftAccountChangeType::UPDATED),
+        ]);
+        assert_eq!(claimed[0].attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn republish_records_every_account_page_by_page() {
+        let store = MemoryStore::new();
+        store.add_account("user", None, &account(NOTCH, "Notch")).await.unwrap();
+        store.add_account("user", None, &account(JEB, "jeb_")).await.unwrap();
+        for entry in store.claim_events(10, Duration::from_secs(30)).await.unwrap() {
+            store.event_published(entry.id).await.unwrap();
+        }
+
+        let first = store.republish_page(0, 1).await.unwrap().expect("first page");
+        let last = store.republish_page(first, 1).await.unwrap().expect("second page");
+        assert_eq!(store.republish_page(last, 1).await.unwrap(), None);
+
+        let changes: Vec<_> = store.claim_events(10, Duration::from_secs(30)).await.unwrap().iter()
+            .map(|e| MinecraftAccountChanged::parse_from_bytes(&e.payload).unwrap())
+            .map(|c| (c.account.minecraft_uuid.clone(), c.account.is_main, c.change.enum_value().unwrap()))
+            .collect();
+        assert_eq!(changes, vec![
+            (NOTCH.to_string(), true, MinecraftAccountChangeType::UPDATED),
+            (JEB.to_string(), false, MinecraftAccountChangeType::UPDATED),
+        ]);
+    }
+}
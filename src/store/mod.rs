@@ -0,0 +1,159 @@
+mod postgres;
+#[cfg(test)]
+pub mod memory;
+
+use std::fmt::Debug;
+use std::time::Duration;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use crate::proto::minecraft_account::MinecraftAccount;
+use crate::whitelist::WhitelistAction;
+
+pub use postgres::Store;
+
+/// An account along with its row id.
+pub struct StoredAccount {
+    pub id: i64,
+    pub account: MinecraftAccount,
+}
+
+/// Who an account belongs to, the user id and the legacy discord id it was registered with.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AccountOwner {
+    pub user_id: String,
+    pub discord_id: Option<String>,
+}
+
+/// An account from before user ids were enforced that still has no user id.
+pub struct LegacyAccount {
+    pub discord_id: Option<String>,
+    pub account: MinecraftAccount,
+}
+
+pub struct AddResult {
+    pub account: MinecraftAccount,
+    /// The queued whitelist change.
+    pub outbox_id: i64,
+}
+
+pub struct DeleteResult {
+    pub deleted: bool,
+    /// The queued whitelist change, if an account was deleted.
+    pub outbox_id: Option<i64>,
+}
+
+pub struct UsernameHistoryEntry {
+    pub minecraft_uuid: String,
+    pub minecraft_username: String,
+    pub valid_from: DateTime<Utc>,
+    /// None while it is still the account's name.
+    pub valid_to: Option<DateTime<Utc>>,
+}
+
+pub struct OutboxEntry {
+    pub id: i64,
+    pub minecraft_uuid: String,
+    pub action: WhitelistAction,
+    pub attempts: i32,
+}
+
+/// A change event recorded with the account change, waiting to be published.
+pub struct EventEntry {
+    pub id: i64,
+    /// The encoded MinecraftAccountChanged.
+    pub payload: Vec<u8>,
+    pub attempts: i32,
+}
+
+/// What the deprecated first_name field of every account reply holds, until clients stop reading it.
+pub(crate) const DEPRECATED_FIRST_NAME: &str = "Deprecated";
+
+// How long the handler that queued a whitelist change has to deliver it before the outbox job does.
+pub(crate) const WHITELIST_HANDOFF: Duration = Duration::from_secs(30);
+
+/// Everything the handlers and jobs need from the accounts database. An account's owner is its
+/// user id, and every owner has exactly one main account. Every change records its change events
+/// in the same transaction, for the event outbox job to publish.
+#[async_trait]
+pub trait AccountStore: Debug + Send + Sync {
+
+    /// Adds the account, it becomes the main account if the owner has none, and queues whitelisting it.
+    async fn add_account(&self, user_id: &str, discord_id: Option<String>, account: &MinecraftAccount) -> Result<AddResult>;
+
+    /// Changes only the account's username, leaving is_main to set_main.
+    async fn rename_account(&self, minecraft_uuid: &str, username: &str) -> Result<MinecraftAccount>;
+
+    /// Deletes the account, promoting the owner's oldest remaining account if it was their main.
+    async fn delete_account(&self, minecraft_uuid: &str) -> Result<DeleteResult>;
+
+    /// Makes the account its owner's main account, clearing the old main at the same time.
+    async fn set_main(&self, minecraft_uuid: &str) -> Result<MinecraftAccount>;
+
+    async fn minecraft_name_to_uuid(&self, name: &str) -> Result<Option<String>>;
+
+    /// Returns who owns the account, None if there is no such account.
+    async fn uuid_owner(&self, minecraft_uuid: &str) -> Result<Option<AccountOwner>>;
+
+    async fn get_by_user(&self, id: &str) -> Result<Vec<MinecraftAccount>>;
+
+    async fn get_by_minecraft(&self, uuid: &str) -> Result<Option<MinecraftAccount>>;
+
+    /// Returns up to `limit` accounts with a row id greater than `after_id`, ordered by id.
+    async fn get_page(&self, after_id: i64, limit: i64) -> Result<Vec<StoredAccount>>;
+
+    /// Returns the legacy discord ids that still own accounts without a user id.
+    async fn discord_only_owners(&self) -> Result<Vec<String>>;
+
+    /// Hands the discord id's accounts without a user id to the user. They stop being main if the
+    /// user already has a main account. Returns the accounts that changed.
+    async fn assign_user_id(&self, discord_id: &str, user_id: &str) -> Result<Vec<MinecraftAccount>>;
+
+    /// Records an UPDATED event with the current state of up to `limit` accounts with a row id
+    /// greater than `after_id`. Returns the id of the last one, None once there are none left.
+    async fn republish_page(&self, after_id: i64, limit: i64) -> Result<Option<i64>>;
+
+    /// Returns every account that still has no user id.
+    async fn accounts_without_user_id(&self) -> Result<Vec<LegacyAccount>>;
+
+    /// Deletes an account that has no user id, queueing its removal from the whitelist.
+    /// Returns false if there was no such account.
+    async fn delete_legacy_account(&self, minecraft_uuid: &str) -> Result<bool>;
+
+    /// Returns every account that has ever had the username, newest first.
+    async fn username_history(&self, username: &str) -> Result<Vec<UsernameHistoryEntry>>;
+
+    /// Waits until a whitelist change was released to the outbox job, or the timeout passed.
+    async fn outbox_notified(&self, timeout: Duration);
+
+    /// Claims up to `limit` due whitelist changes, only the oldest pending change per account.
+    /// Claimed changes are hidden from other replicas for `lease`.
+    async fn claim_whitelist(&self, limit: i64, lease: Duration) -> Result<Vec<OutboxEntry>>;
+
+    /// Claims a specific whitelist change, as long as no older change for the account is pending.
+    async fn claim_whitelist_entry(&self, id: i64) -> Result<Option<OutboxEntry>>;
+
+    /// Hands a whitelist change over to the outbox job right away.
+    async fn release_whitelist(&self, id: i64) -> Result<()>;
+
+    async fn whitelist_delivered(&self, id: i64) -> Result<()>;
+
+    async fn whitelist_failed(&self, id: i64, error: &str, retry_in: Duration) -> Result<()>;
+
+    /// Waits until a change event was recorded, or the timeout passed.
+    async fn events_notified(&self, timeout: Duration);
+
+    /// Claims up to `limit` due change events, only the oldest unpublished event per account.
+    /// Claimed events are hidden from other replicas for `lease`.
+    async fn claim_events(&self, limit: i64, lease: Duration) -> Result<Vec<EventEntry>>;
+
+    async fn event_published(&self, id: i64) -> Result<()>;
+
+    async fn event_failed(&self, id: i64, error: &str, retry_in: Duration) -> Result<()>;
+
+    async fn all_uuids(&self) -> Result<Vec<String>>;
+
+    async fn uuid_exists(&self, id: &str) -> Result<bool> {
+        Ok(self.uuid_owner(id).await?.is_some())
+    }
+}
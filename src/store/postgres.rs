@@ -0,0 +1,1144 @@
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use protobuf::{Message, SpecialFields};
+use sqlx::{Connection, PgConnection, PgPool};
+use sqlx::types::Uuid;
+use tokio::sync::Notify;
+use crate::events;
+use crate::mojang::{Lookup, Profile};
+use crate::proto::minecraft_account::MinecraftAccount;
+use crate::proto::minecraft_account_update::MinecraftAccountChangeType;
+use crate::store::{AccountOwner, AccountStore, AddResult, LegacyAccount, DeleteResult, EventEntry, OutboxEntry, StoredAccount, UsernameHistoryEntry, DEPRECATED_FIRST_NAME, WHITELIST_HANDOFF};
+use crate::util;
+use crate::whitelist::WhitelistAction;
+
+#[derive(Clone, Debug)]
+pub struct Store {
+    db: PgPool,
+    outbox: Arc<Notify>,
+    events: Arc<Notify>,
+    /// Whether accounts still carry the first names they were stored with.
+    read_first_name: bool,
+}
+
+struct T {
+    id: i64,
+
+    discord_id: Option<String>,
+    user_id: String,
+
+    minecraft_uuid: Uuid,
+    minecraft_username: String,
+
+    is_main: bool,
+}
+
+impl T {
+    fn into_account(self) -> MinecraftAccount {
+        account(self.minecraft_uuid, self.minecraft_username, self.is_main)
+    }
+}
+
+/// The account as replies carry it, before [`Store::stored_first_names`] fills in the first name.
+fn account(minecraft_uuid: Uuid, minecraft_username: String, is_main: bool) -> MinecraftAccount {
+    MinecraftAccount{
+        deprecated_first_name: DEPRECATED_FIRST_NAME.to_string(),
+
+        minecraft_uuid: minecraft_uuid.to_string(),
+        minecraft_username,
+        is_main,
+
+        special_fields: SpecialFields::default(),
+    }
+}
+
+/// Closes the current history entry if the username changed and opens one for the new name.
+async fn record_username(conn: &mut PgConnection, minecraft_uuid: Uuid, username: &str) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE
+            username_history
+        SET
+            valid_to = now()
+        WHERE
+            minecraft_uuid = $1
+            AND valid_to IS NULL
+            AND minecraft_username <> $2
+        ;"#,
+        minecraft_uuid,
+        username,
+    )
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO username_history (minecraft_uuid, minecraft_username)
+        SELECT $1, $2
+        WHERE NOT EXISTS (
+            SELECT 1 FROM username_history WHERE minecraft_uuid = $1 AND valid_to IS NULL
+        )
+        ;"#,
+        minecraft_uuid,
+        username,
+    )
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Ends the account's current name in the history, it stops being theirs when the account is deleted.
+async fn close_username_history(conn: &mut PgConnection, minecraft_uuid: Uuid) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE
+            username_history
+        SET
+            valid_to = now()
+        WHERE
+            minecraft_uuid = $1
+            AND valid_to IS NULL
+        ;"#,
+        minecraft_uuid,
+    )
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Records a whitelist change to be delivered once the transaction commits.
+async fn enqueue_whitelist(conn: &mut PgConnection, minecraft_uuid: Uuid, action: WhitelistAction) -> Result<i64> {
+    struct T2 {
+        pub id: i64,
+    }
+    let re : T2 = sqlx::query_as!(
+        T2,
+        r#"
+        INSERT INTO whitelist_outbox (minecraft_uuid, action, next_attempt_at)
+        VALUES ($1, $2, now() + $3)
+        RETURNING id
+        ;"#,
+        minecraft_uuid,
+        action.to_string(),
+        sqlx::postgres::types::PgInterval::try_from(WHITELIST_HANDOFF).map_err(|e| anyhow::anyhow!(e))?,
+    )
+        .fetch_one(&mut *conn)
+        .await?;
+
+    Ok(re.id)
+}
+
+/// Records a change event for the account, to be published once the transaction commits.
+async fn enqueue_event(
+    conn: &mut PgConnection,
+    user_id: Option<String>,
+    discord_id: Option<String>,
+    change: MinecraftAccountChangeType,
+    account: &MinecraftAccount,
+) -> Result<()> {
+    let payload: Vec<u8> = events::change(user_id, discord_id, change, account.clone()).write_to_bytes()?;
+    sqlx::query!(
+        r#"
+        INSERT INTO event_outbox (minecraft_uuid, payload)
+        VALUES ($1, $2)
+        ;"#,
+        Uuid::parse_str(&account.minecraft_uuid)?,
+        payload,
+    )
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+/// Whether inserting failed because the user already has a main account.
+fn is_one_main_violation(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(e)) => e.constraint() == Some("accounts_one_main"),
+        _ => false,
+    }
+}
+
+impl Store {
+
+    pub fn new(db: PgPool) -> Self {
+        Store { db, outbox: Arc::new(Notify::new()), events: Arc::new(Notify::new()), read_first_name: util::read_first_name() }
+    }
+
+    /// Fills in the first names the accounts were stored with, while they are still read.
+    /// Every account the store hands out goes through here, so replies and events agree.
+    async fn stored_first_names<'a>(&self, accounts: impl IntoIterator<Item = &'a mut MinecraftAccount> + Send) {
+        if !self.read_first_name {
+            return;
+        }
+        match self.db.acquire().await {
+            Ok(mut conn) => self.stored_first_names_in(&mut conn, accounts).await,
+            Err(e) => tracing::warn!("Error reading first names, replying with {:?}: {:?}", DEPRECATED_FIRST_NAME, e),
+        }
+    }
+
+    /// [`Store::stored_first_names`] inside a transaction, so the events it records carry them too.
+    /// Reads in a savepoint, a failed read doesn't abort the transaction.
+    async fn stored_first_names_in<'a>(&self, conn: &mut PgConnection, accounts: impl IntoIterator<Item = &'a mut MinecraftAccount> + Send) {
+        let mut accounts: Vec<&mut MinecraftAccount> = accounts.into_iter().collect();
+        if !self.read_first_name || accounts.is_empty() {
+            return;
+        }
+        let uuids: Vec<Uuid> = accounts.iter().filter_map(|a| Uuid::parse_str(&a.minecraft_uuid).ok()).collect();
+
+        let re : sqlx::Result<Vec<(Uuid, Option<String>)>> = async {
+            let mut savepoint = conn.begin().await?;
+            // Not checked at compile time, the column is gone from the schema once it was dropped.
+            let rows = sqlx::query_as(
+                "SELECT minecraft_uuid, first_name FROM accounts WHERE minecraft_uuid = ANY($1)"
+            )
+                .bind(&uuids)
+                .fetch_all(&mut *savepoint)
+                .await?;
+            savepoint.commit().await?;
+            Ok(rows)
+        }.await;
+
+        match re {
+            Ok(rows) => for (uuid, first_name) in rows {
+                let account = accounts.iter_mut().find(|a| a.minecraft_uuid == uuid.to_string());
+                if let (Some(account), Some(first_name)) = (account, first_name) {
+                    account.deprecated_first_name = first_name;
+                }
+            },
+            Err(e) => tracing::warn!("Error reading first names, replying with {:?}: {:?}", DEPRECATED_FIRST_NAME, e),
+        }
+    }
+
+    /// Inserts the account, as the user's main one if `may_be_main` and they have none yet.
+    async fn insert_account(&self, user_id: &str, discord_id: Option<String>, account: &MinecraftAccount, may_be_main: bool) -> Result<AddResult> {
+        let mut tx = self.db.begin().await?;
+
+        let re : sqlx::Result<T> = sqlx::query_as!(
+            T,
+            r#"
+            INSERT INTO accounts (
+                discord_id, user_id,
+                minecraft_uuid, minecraft_username,
+                is_main
+            ) SELECT
+                $1::VARCHAR, $2::VARCHAR,
+                $3, $4,
+                $5 AND NOT EXISTS (
+                    SELECT 1 FROM accounts
+                    WHERE is_main AND user_id = $2
+                )
+            RETURNING
+                id,
+                discord_id, user_id,
+                minecraft_uuid, minecraft_username,
+                is_main
+            ;"#,
+            discord_id,
+            user_id,
+            Uuid::parse_str(&account.minecraft_uuid)?,
+            account.minecraft_username,
+            may_be_main,
+        )
+            .fetch_one(&mut *tx)
+            .await;
+
+        let re = re?;
+        record_username(&mut tx, re.minecraft_uuid, &re.minecraft_username).await?;
+        let outbox_id = enqueue_whitelist(&mut tx, re.minecraft_uuid, WhitelistAction::Add).await?;
+
+        let (user_id, discord_id) = (re.user_id.clone(), re.discord_id.clone());
+        let account = re.into_account();
+        enqueue_event(&mut tx, Some(user_id), discord_id, MinecraftAccountChangeType::ADDED, &account).await?;
+        tx.commit().await?;
+        self.events.notify_one();
+
+        Ok(AddResult {
+            account,
+            outbox_id,
+        })
+    }
+
+    /// Returns an unexpired mojang lookup along with when it expires.
+    pub async fn get_cached_lookup(&self, username: &str) -> Result<Option<(Lookup, DateTime<Utc>)>> {
+        struct T2 {
+            pub minecraft_uuid: Option<Uuid>,
+            pub minecraft_username: Option<String>,
+            pub expires_at: DateTime<Utc>,
+        }
+        let re : sqlx::Result<Option<T2>> = sqlx::query_as!(
+            T2,
+            r#"
+            SELECT
+                minecraft_uuid, minecraft_username,
+                expires_at
+            FROM
+                mojang_profile_cache
+            WHERE
+                username = $1
+                AND expires_at > now()
+            ;"#,
+            username.to_lowercase(),
+        )
+            .fetch_optional(&self.db)
+            .await;
+
+        let re = re?;
+        Ok(re.map(|t| {
+            let lookup = match (t.minecraft_uuid, t.minecraft_username) {
+                (Some(id), Some(name)) => Lookup::Found(Profile { id: id.simple().to_string(), name }),
+                _ => Lookup::NotFound,
+            };
+            (lookup, t.expires_at)
+        }))
+    }
+
+    /// Saves a found or not found mojang lookup, rate limited lookups are not cached.
+    pub async fn put_cached_lookup(&self, username: &str, lookup: &Lookup, ttl: Duration) -> Result<()> {
+        let (uuid, name) = match lookup {
+            Lookup::Found(profile) => (Some(Uuid::parse_str(&profile.id)?), Some(profile.name.clone())),
+            Lookup::NotFound => (None, None),
+            Lookup::RateLimited => return Ok(()),
+        };
+
+        sqlx::query!(
+            r#"
+            INSERT INTO mojang_profile_cache (
+                username,
+                minecraft_uuid, minecraft_username,
+                expires_at
+            ) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (username) DO UPDATE SET
+                minecraft_uuid = EXCLUDED.minecraft_uuid,
+                minecraft_username = EXCLUDED.minecraft_username,
+                expires_at = EXCLUDED.expires_at
+            ;"#,
+            username.to_lowercase(),
+            uuid,
+            name,
+            Utc::now() + chrono::Duration::from_std(ttl)?,
+        )
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AccountStore for Store {
+
+    async fn add_account(&self, user_id: &str, discord_id: Option<String>, account: &MinecraftAccount) -> Result<AddResult> {
+        match self.insert_account(user_id, discord_id.clone(), account, true).await {
+            // A concurrent add made its account the main one first.
+            Err(e) if is_one_main_violation(&e) => self.insert_account(user_id, discord_id, account, false).await,
+            re => re,
+        }
+    }
+
+    async fn rename_account(&self, minecraft_uuid: &str, username: &str) -> Result<MinecraftAccount> {
+        let mut tx = self.db.begin().await?;
+
+        let re : sqlx::Result<T> = sqlx::query_as!(
+            T,
+            r#"
+            UPDATE
+                accounts
+            SET
+                minecraft_username = $2
+            WHERE
+                minecraft_uuid = $1
+            RETURNING
+                id,
+                discord_id, user_id,
+                minecraft_uuid, minecraft_username,
+                is_main
+            ;"#,
+            Uuid::parse_str(&minecraft_uuid)?,
+            username,
+        )
+            .fetch_one(&mut *tx)
+            .await;
+
+        let re = re?;
+        record_username(&mut tx, re.minecraft_uuid, &re.minecraft_username).await?;
+
+        let (user_id, discord_id) = (re.user_id.clone(), re.discord_id.clone());
+        let mut account = re.into_account();
+        self.stored_first_names_in(&mut tx, [&mut account]).await;
+        enqueue_event(&mut tx, Some(user_id), discord_id, MinecraftAccountChangeType::UPDATED, &account).await?;
+        tx.commit().await?;
+        self.events.notify_one();
+
+        Ok(account)
+    }
+
+    async fn delete_account(&self, minecraft_uuid: &str) -> Result<DeleteResult> {
+        let mut tx = self.db.begin().await?;
+
+        // Read before deleting, the first name can't be read once the row is gone.
+        let re : sqlx::Result<Option<T>> = sqlx::query_as!(
+            T,
+            r#"
+            SELECT
+                id,
+                discord_id, user_id,
+                minecraft_uuid, minecraft_username,
+                is_main
+            FROM
+                accounts
+            WHERE
+                minecraft_uuid = $1
+            FOR UPDATE
+            ;"#,
+            Uuid::parse_str(&minecraft_uuid)?,
+        )
+            .fetch_optional(&mut *tx)
+            .await;
+
+        let deleted = match re? {
+            None => return Ok(DeleteResult { deleted: false, outbox_id: None }),
+            Some(t) => t,
+        };
+        let (id, uuid, is_main) = (deleted.id, deleted.minecraft_uuid, deleted.is_main);
+        let (user_id, discord_id) = (deleted.user_id.clone(), deleted.discord_id.clone());
+        let mut removed = deleted.into_account();
+        self.stored_first_names_in(&mut tx, [&mut removed]).await;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM accounts WHERE id = $1
+            ;"#,
+            id,
+        )
+            .execute(&mut *tx)
+            .await?;
+
+        close_username_history(&mut tx, uuid).await?;
+        let outbox_id = enqueue_whitelist(&mut tx, uuid, WhitelistAction::Remove).await?;
+        enqueue_event(&mut tx, Some(user_id.clone()), discord_id, MinecraftAccountChangeType::REMOVED, &removed).await?;
+
+        if is_main {
+            let re : sqlx::Result<Option<T>> = sqlx::query_as!(
+                T,
+                r#"
+                UPDATE
+                    accounts
+                SET
+                    is_main = true
+                WHERE id = (
+                    SELECT id FROM accounts
+                    WHERE user_id = $1
+                    ORDER BY id
+                    LIMIT 1
+                )
+                RETURNING
+                    id,
+                    discord_id, user_id,
+                    minecraft_uuid, minecraft_username,
+                    is_main
+                ;"#,
+                user_id,
+            )
+                .fetch_optional(&mut *tx)
+                .await;
+
+            if let Some(t) = re? {
+                let discord_id = t.discord_id.clone();
+                let mut account = t.into_account();
+                self.stored_first_names_in(&mut tx, [&mut account]).await;
+                enqueue_event(&mut tx, Some(user_id), discord_id, MinecraftAccountChangeType::UPDATED, &account).await?;
+            }
+        }
+
+        tx.commit().await?;
+        self.events.notify_one();
+
+        Ok(DeleteResult { deleted: true, outbox_id: Some(outbox_id) })
+    }
+
+    async fn set_main(&self, minecraft_uuid: &str) -> Result<MinecraftAccount> {
+        let uuid = Uuid::parse_str(minecraft_uuid)?;
+        let mut tx = self.db.begin().await?;
+
+        let re : sqlx::Result<Option<T>> = sqlx::query_as!(
+            T,
+            r#"
+            UPDATE
+                accounts
+            SET
+                is_main = false
+            WHERE
+                is_main
+                AND minecraft_uuid <> $1
+                AND user_id = (
+                    SELECT user_id FROM accounts WHERE minecraft_uuid = $1
+                )
+            RETURNING
+                id,
+                discord_id, user_id,
+                minecraft_uuid, minecraft_username,
+                is_main
+            ;"#,
+            uuid,
+        )
+            .fetch_optional(&mut *tx)
+            .await;
+
+        let previous = re?;
+
+        let re : sqlx::Result<T> = sqlx::query_as!(
+            T,
+            r#"
+            UPDATE
+                accounts
+            SET
+                is_main = true
+            WHERE
+                minecraft_uuid = $1
+            RETURNING
+                id,
+                discord_id, user_id,
+                minecraft_uuid, minecraft_username,
+                is_main
+            ;"#,
+            uuid,
+        )
+            .fetch_one(&mut *tx)
+            .await;
+
+        let re = re?;
+        let (user_id, discord_id) = (re.user_id.clone(), re.discord_id.clone());
+        let mut account = re.into_account();
+
+        // Nothing changed if it was already the main account.
+        let t = match previous {
+            None => {
+                tx.commit().await?;
+                self.stored_first_names([&mut account]).await;
+                return Ok(account);
+            },
+            Some(t) => t,
+        };
+        let previous_discord_id = t.discord_id.clone();
+        let mut previous = t.into_account();
+        self.stored_first_names_in(&mut tx, [&mut previous, &mut account]).await;
+        enqueue_event(&mut tx, Some(user_id.clone()), previous_discord_id, MinecraftAccountChangeType::UPDATED, &previous).await?;
+        enqueue_event(&mut tx, Some(user_id), discord_id, MinecraftAccountChangeType::UPDATED, &account).await?;
+
+        tx.commit().await?;
+        self.events.notify_one();
+
+        Ok(account)
+    }
+
+    async fn minecraft_name_to_uuid(&self, name: &str) -> Result<Option<String>> {
+        struct T2 {
+            pub minecraft_uuid: String,
+        }
+        let re : sqlx::Result<Option<T2>> = sqlx::query_as!(
+            T2,
+            r#"
+            SELECT
+                minecraft_uuid
+            FROM
+                accounts
+            WHERE
+                minecraft_username = $1
+            ;"#,
+            name
+        )
+            .fetch_optional(&self.db)
+            .await;
+
+        let re = re?;
+        match re {
+            None => Ok(None),
+            Some(t) => Ok(Some(t.minecraft_uuid))
+        }
+    }
+
+    async fn uuid_owner(&self, minecraft_uuid: &str) -> Result<Option<AccountOwner>> {
+        let re : sqlx::Result<Option<AccountOwner>> = sqlx::query_as!(
+            AccountOwner,
+            r#"
+            SELECT discord_id, user_id FROM accounts WHERE minecraft_uuid = $1
+            ;"#,
+            Uuid::parse_str(&minecraft_uuid)?,
+        )
+            .fetch_optional(&self.db)
+            .await;
+
+        Ok(re?)
+    }
+
+    async fn get_by_user(&self, id: &str) -> Result<Vec<MinecraftAccount>> {
+
+        let re : sqlx::Result<Vec<T>> = sqlx::query_as!(
+            T,
+            r#"
+            SELECT
+                id,
+                discord_id, user_id,
+                minecraft_uuid, minecraft_username,
+                is_main
+            FROM
+                accounts
+            WHERE user_id = $1
+            ;"#,
+            id
+        )
+            .fetch_all(&self.db)
+            .await;
+
+        let re = re?;
+
+        let mut re: Vec<MinecraftAccount> = re.into_iter().map(|t| t.into_account()).collect();
+        self.stored_first_names(&mut re).await;
+
+        Ok(re)
+    }
+
+    async fn get_by_minecraft(&self, uuid: &str) -> Result<Option<MinecraftAccount>> {
+
+        let re : sqlx::Result<Option<T>> = sqlx::query_as!(
+            T,
+            r#"
+            SELECT
+                id,
+                discord_id, user_id,
+                minecraft_uuid, minecraft_username,
+                is_main
+            FROM
+                accounts
+            WHERE
+                minecraft_uuid = $1
+            ;"#,
+            Uuid::parse_str(&uuid)?,
+        )
+            .fetch_optional(&self.db)
+            .await;
+
+        let re = re?;
+        match re {
+            None => Ok(None),
+            Some(t) => {
+                let mut account = t.into_account();
+                self.stored_first_names([&mut account]).await;
+                Ok(Some(account))
+            }
+        }
+    }
+
+    async fn get_page(&self, after_id: i64, limit: i64) -> Result<Vec<StoredAccount>> {
+
+        let re : sqlx::Result<Vec<T>> = sqlx::query_as!(
+            T,
+            r#"
+            SELECT
+                id,
+                discord_id, user_id,
+                minecraft_uuid, minecraft_username,
+                is_main
+            FROM
+                accounts
+            WHERE id > $1
+            ORDER BY id
+            LIMIT $2
+            ;"#,
+            after_id,
+            limit,
+        )
+            .fetch_all(&self.db)
+            .await;
+
+        let re = re?;
+
+        let mut re: Vec<StoredAccount> = re.into_iter().map(|t| StoredAccount {
+            id: t.id,
+            account: t.into_account(),
+        }).collect();
+
+        self.stored_first_names(re.iter_mut().map(|s| &mut s.account)).await;
+        Ok(re)
+    }
+
+    async fn discord_only_owners(&self) -> Result<Vec<String>> {
+        struct T2 {
+            pub discord_id: Option<String>,
+        }
+        let re : sqlx::Result<Vec<T2>> = sqlx::query_as!(
+            T2,
+            r#"
+            SELECT DISTINCT
+                discord_id
+            FROM
+                accounts
+            WHERE
+                user_id IS NULL
+                AND discord_id IS NOT NULL
+            ORDER BY discord_id
+            ;"#,
+        )
+            .fetch_all(&self.db)
+            .await;
+
+        Ok(re?.into_iter().filter_map(|t| t.discord_id).collect())
+    }
+
+    async fn accounts_without_user_id(&self) -> Result<Vec<LegacyAccount>> {
+        struct T2 {
+            pub discord_id: Option<String>,
+            pub minecraft_uuid: Uuid,
+            pub minecraft_username: String,
+            pub is_main: bool,
+        }
+        let re : sqlx::Result<Vec<T2>> = sqlx::query_as!(
+            T2,
+            r#"
+            SELECT
+                discord_id,
+                minecraft_uuid, minecraft_username,
+                is_main
+            FROM
+                accounts
+            WHERE
+                user_id IS NULL
+            ORDER BY id
+            ;"#,
+        )
+            .fetch_all(&self.db)
+            .await;
+
+        let re = re?;
+
+        let mut re: Vec<LegacyAccount> = re.into_iter().map(|t| LegacyAccount {
+            discord_id: t.discord_id,
+            account: account(t.minecraft_uuid, t.minecraft_username, t.is_main),
+        }).collect();
+
+        self.stored_first_names(re.iter_mut().map(|l| &mut l.account)).await;
+        Ok(re)
+    }
+
+    async fn delete_legacy_account(&self, minecraft_uuid: &str) -> Result<bool> {
+        struct T2 {
+            pub id: i64,
+            pub discord_id: Option<String>,
+            pub minecraft_uuid: Uuid,
+            pub minecraft_username: String,
+            pub is_main: bool,
+        }
+        let mut tx = self.db.begin().await?;
+
+        // Read before deleting, the first name can't be read once the row is gone.
+        let re : sqlx::Result<Option<T2>> = sqlx::query_as!(
+            T2,
+            r#"
+            SELECT
+                id,
+                discord_id,
+                minecraft_uuid, minecraft_username,
+                is_main
+            FROM
+                accounts
+            WHERE
+                minecraft_uuid = $1
+                AND user_id IS NULL
+            FOR UPDATE
+            ;"#,
+            Uuid::parse_str(&minecraft_uuid)?,
+        )
+            .fetch_optional(&mut *tx)
+            .await;
+
+        let deleted = match re? {
+            None => return Ok(false),
+            Some(t) => t,
+        };
+        let mut removed = account(deleted.minecraft_uuid, deleted.minecraft_username, deleted.is_main);
+        self.stored_first_names_in(&mut tx, [&mut removed]).await;
+
+        // Without an owner there is no main account to promote.
+        sqlx::query!(
+            r#"
+            DELETE FROM accounts WHERE id = $1
+            ;"#,
+            deleted.id,
+        )
+            .execute(&mut *tx)
+            .await?;
+
+        close_username_history(&mut tx, deleted.minecraft_uuid).await?;
+        enqueue_whitelist(&mut tx, deleted.minecraft_uuid, WhitelistAction::Remove).await?;
+        enqueue_event(&mut tx, None, deleted.discord_id, MinecraftAccountChangeType::REMOVED, &removed).await?;
+        tx.commit().await?;
+        self.events.notify_one();
+
+        Ok(true)
+    }
+
+    async fn assign_user_id(&self, discord_id: &str, user_id: &str) -> Result<Vec<MinecraftAccount>> {
+        let mut tx = self.db.begin().await?;
+
+        let re : sqlx::Result<Vec<T>> = sqlx::query_as!(
+            T,
+            r#"
+            UPDATE
+                accounts
+            SET
+                user_id = $2::VARCHAR,
+                is_main = is_main AND NOT EXISTS (
+                    SELECT 1 FROM accounts WHERE is_main AND user_id = $2::VARCHAR
+                )
+            WHERE
+                discord_id = $1
+                AND user_id IS NULL
+            RETURNING
+                id,
+                discord_id, user_id,
+                minecraft_uuid, minecraft_username,
+                is_main
+            ;"#,
+            discord_id,
+            user_id,
+        )
+            .fetch_all(&mut *tx)
+            .await;
+
+        let mut re: Vec<MinecraftAccount> = re?.into_iter().map(|t| t.into_account()).collect();
+        self.stored_first_names_in(&mut tx, &mut re).await;
+        for account in &re {
+            enqueue_event(&mut tx, Some(user_id.to_string()), Some(discord_id.to_string()), MinecraftAccountChangeType::UPDATED, account).await?;
+        }
+        tx.commit().await?;
+        self.events.notify_one();
+
+        Ok(re)
+    }
+
+    async fn republish_page(&self, after_id: i64, limit: i64) -> Result<Option<i64>> {
+        let mut tx = self.db.begin().await?;
+
+        let re : sqlx::Result<Vec<T>> = sqlx::query_as!(
+            T,
+            r#"
+            SELECT
+                id,
+                discord_id, user_id,
+                minecraft_uuid, minecraft_username,
+                is_main
+            FROM
+                accounts
+            WHERE id > $1
+            ORDER BY id
+            LIMIT $2
+            ;"#,
+            after_id,
+            limit,
+        )
+            .fetch_all(&mut *tx)
+            .await;
+
+        let re = re?;
+        let last_id = re.last().map(|t| t.id);
+
+        let mut owners = Vec::new();
+        let mut accounts = Vec::new();
+        for t in re {
+            owners.push((t.user_id.clone(), t.discord_id.clone()));
+            accounts.push(t.into_account());
+        }
+        self.stored_first_names_in(&mut tx, &mut accounts).await;
+        for ((user_id, discord_id), account) in owners.into_iter().zip(&accounts) {
+            enqueue_event(&mut tx, Some(user_id), discord_id, MinecraftAccountChangeType::UPDATED, account).await?;
+        }
+        tx.commit().await?;
+        self.events.notify_one();
+
+        Ok(last_id)
+    }
+
+    async fn username_history(&self, username: &str) -> Result<Vec<UsernameHistoryEntry>> {
+        struct T2 {
+            pub minecraft_uuid: Uuid,
+            pub minecraft_username: String,
+            pub valid_from: DateTime<Utc>,
+            pub valid_to: Option<DateTime<Utc>>,
+        }
+        let re : sqlx::Result<Vec<T2>> = sqlx::query_as!(
+            T2,
+            r#"
+            SELECT
+                minecraft_uuid, minecraft_username,
+                valid_from, valid_to
+            FROM
+                username_history
+            WHERE
+                lower(minecraft_username) = lower($1)
+            ORDER BY valid_from DESC
+            ;"#,
+            username,
+        )
+            .fetch_all(&self.db)
+            .await;
+
+        let re = re?;
+
+        let re = re.into_iter().map(|t| UsernameHistoryEntry {
+            minecraft_uuid: t.minecraft_uuid.to_string(),
+            minecraft_username: t.minecraft_username,
+            valid_from: t.valid_from,
+            valid_to: t.valid_to,
+        }).collect();
+
+        Ok(re)
+    }
+
+    async fn outbox_notified(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.outbox.notified()).await;
+    }
+
+    async fn claim_whitelist(&self, limit: i64, lease: Duration) -> Result<Vec<OutboxEntry>> {
+        struct T2 {
+            pub id: i64,
+            pub minecraft_uuid: Uuid,
+            pub action: String,
+            pub attempts: i32,
+        }
+        let re : sqlx::Result<Vec<T2>> = sqlx::query_as!(
+            T2,
+            r#"
+            UPDATE
+                whitelist_outbox
+            SET
+                next_attempt_at = now() + $2
+            WHERE id IN (
+                SELECT id FROM whitelist_outbox o
+                WHERE
+                    delivered_at IS NULL
+                    AND next_attempt_at <= now()
+                    AND NOT EXISTS (
+                        SELECT 1 FROM whitelist_outbox p
+                        WHERE p.minecraft_uuid = o.minecraft_uuid AND p.delivered_at IS NULL AND p.id < o.id
+                    )
+                ORDER BY id
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING
+                id, minecraft_uuid, action, attempts
+            ;"#,
+            limit,
+            sqlx::postgres::types::PgInterval::try_from(lease).map_err(|e| anyhow::anyhow!(e))?,
+        )
+            .fetch_all(&self.db)
+            .await;
+
+        let re = re?;
+
+        let mut entries = Vec::new();
+        for t in re {
+            entries.push(OutboxEntry {
+                id: t.id,
+                minecraft_uuid: t.minecraft_uuid.to_string(),
+                action: t.action.parse()?,
+                attempts: t.attempts,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    async fn claim_whitelist_entry(&self, id: i64) -> Result<Option<OutboxEntry>> {
+        struct T2 {
+            pub id: i64,
+            pub minecraft_uuid: Uuid,
+            pub action: String,
+            pub attempts: i32,
+        }
+        let re : sqlx::Result<Option<T2>> = sqlx::query_as!(
+            T2,
+            r#"
+            SELECT
+                id, minecraft_uuid, action, attempts
+            FROM
+                whitelist_outbox o
+            WHERE
+                id = $1
+                AND delivered_at IS NULL
+                AND NOT EXISTS (
+                    SELECT 1 FROM whitelist_outbox p
+                    WHERE p.minecraft_uuid = o.minecraft_uuid AND p.delivered_at IS NULL AND p.id < o.id
+                )
+            ;"#,
+            id,
+        )
+            .fetch_optional(&self.db)
+            .await;
+
+        match re? {
+            None => Ok(None),
+            Some(t) => Ok(Some(OutboxEntry {
+                id: t.id,
+                minecraft_uuid: t.minecraft_uuid.to_string(),
+                action: t.action.parse()?,
+                attempts: t.attempts,
+            })),
+        }
+    }
+
+    async fn release_whitelist(&self, id: i64) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE whitelist_outbox SET next_attempt_at = now() WHERE id = $1
+            ;"#,
+            id,
+        )
+            .execute(&self.db)
+            .await?;
+
+        self.outbox.notify_one();
+        Ok(())
+    }
+
+    async fn whitelist_delivered(&self, id: i64) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE whitelist_outbox SET delivered_at = now() WHERE id = $1
+            ;"#,
+            id,
+        )
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn whitelist_failed(&self, id: i64, error: &str, retry_in: Duration) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE
+                whitelist_outbox
+            SET
+                attempts = attempts + 1,
+                last_error = $2,
+                next_attempt_at = now() + $3
+            WHERE id = $1
+            ;"#,
+            id,
+            error,
+            sqlx::postgres::types::PgInterval::try_from(retry_in).map_err(|e| anyhow::anyhow!(e))?,
+        )
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn events_notified(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.events.notified()).await;
+    }
+
+    async fn claim_events(&self, limit: i64, lease: Duration) -> Result<Vec<EventEntry>> {
+        struct T2 {
+            pub id: i64,
+            pub payload: Vec<u8>,
+            pub attempts: i32,
+        }
+        let re : sqlx::Result<Vec<T2>> = sqlx::query_as!(
+            T2,
+            r#"
+            UPDATE
+                event_outbox
+            SET
+                next_attempt_at = now() + $2
+            WHERE id IN (
+                SELECT id FROM event_outbox o
+                WHERE
+                    published_at IS NULL
+                    AND next_attempt_at <= now()
+                    AND NOT EXISTS (
+                        SELECT 1 FROM event_outbox p
+                        WHERE p.minecraft_uuid = o.minecraft_uuid AND p.published_at IS NULL AND p.id < o.id
+                    )
+                ORDER BY id
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING
+                id, payload, attempts
+            ;"#,
+            limit,
+            sqlx::postgres::types::PgInterval::try_from(lease).map_err(|e| anyhow::anyhow!(e))?,
+        )
+            .fetch_all(&self.db)
+            .await;
+
+        // The update doesn't return them in id order.
+        let mut entries: Vec<EventEntry> = re?.into_iter().map(|t| EventEntry {
+            id: t.id,
+            payload: t.payload,
+            attempts: t.attempts,
+        }).collect();
+        entries.sort_by_key(|e| e.id);
+
+        Ok(entries)
+    }
+
+    async fn event_published(&self, id: i64) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE event_outbox SET published_at = now() WHERE id = $1
+            ;"#,
+            id,
+        )
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn event_failed(&self, id: i64, error: &str, retry_in: Duration) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE
+                event_outbox
+            SET
+                attempts = attempts + 1,
+                last_error = $2,
+                next_attempt_at = now() + $3
+            WHERE id = $1
+            ;"#,
+            id,
+            error,
+            sqlx::postgres::types::PgInterval::try_from(retry_in).map_err(|e| anyhow::anyhow!(e))?,
+        )
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn all_uuids(&self) -> Result<Vec<String>> {
+        struct T2 {
+            pub minecraft_uuid: Uuid,
+        }
+        let re : sqlx::Result<Vec<T2>> = sqlx::query_as!(
+            T2,
+            r#"
+            SELECT minecraft_uuid FROM accounts
+            ;"#,
+        )
+            .fetch_all(&self.db)
+            .await;
+
+        let re = re?;
+        Ok(re.into_iter().map(|t| t.minecraft_uuid.to_string()).collect())
+    }
+}
@@ -0,0 +1,25 @@
+use std::env;
+use std::time::Duration;
+use anyhow::Result;
+use async_nats::Client;
+use protobuf::Message;
+use crate::proto::user_by_discord::{GetUserByDiscordIdRequest, GetUserByDiscordIdResponse};
+
+const DEFAULT_TIMEOUT_MS: u64 = 2000;
+
+/// Asks the users service which user a legacy discord id belongs to, None if no user has it.
+pub async fn user_id_for_discord(nc: &Client, discord_id: &str) -> Result<Option<String>> {
+    let timeout = Duration::from_millis(
+        env::var("USERS_TIMEOUT_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_TIMEOUT_MS)
+    );
+
+    let mut req = GetUserByDiscordIdRequest::new();
+    req.discord_id = discord_id.to_string();
+    let encoded: Vec<u8> = req.write_to_bytes()?;
+
+    let reply = tokio::time::timeout(timeout, nc.request("users.get_by_discord", encoded.into())).await
+        .map_err(|_| anyhow::anyhow!("users.get_by_discord timed out"))??;
+    let resp = GetUserByDiscordIdResponse::parse_from_bytes(&reply.payload)?;
+
+    Ok(resp.user_id.filter(|id| !id.is_empty()))
+}
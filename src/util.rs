@@ -1,14 +1,25 @@
 use anyhow::Result;
+use bytes::Bytes;
 use futures::StreamExt;
+use async_nats::service::{self, Service, ServiceExt};
 use std::env;
 use std::future::Future;
+use std::sync::Arc;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::Duration;
-use tokio::task;
-use tracing::{error, Level};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{error, warn, Level};
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::fmt::format::FmtSpan;
 use sqlx::{Pool, Postgres};
 use sqlx::postgres::PgPoolOptions;
+use crate::handlers::error::AccountError;
+use crate::proto::minecraft_account_update::ChangeMinecraftAccountResponse;
+use protobuf::Message;
 
 pub async fn connect_to_nats() -> Result<async_nats::Client> {
     // Get Nats Env Variable
@@ -27,32 +38,300 @@ pub async fn connect_to_nats() -> Result<async_nats::Client> {
     Ok(client)
 }
 
-pub async fn handle_requests<F, Fut>(nc: async_nats::Client, subject: &str, f: F) -> Result<()>
+/// Header a client can set to the milliseconds it is willing to wait for a reply.
+pub const REQUEST_TIMEOUT_HEADER: &str = "Request-Timeout-Ms";
+
+/// Header a client can set to tie our error replies and logs to its own request.
+pub const CORRELATION_ID_HEADER: &str = "Correlation-Id";
+
+/// The migration that drops the deprecated first_name column.
+const DROP_FIRST_NAME_MIGRATION: i64 = 20261018140000;
+
+/// The migration that requires every account to have a user id.
+const ENFORCE_USER_ID_MIGRATION: i64 = 20261018130000;
+
+/// A per subject setting, <PREFIX>_<SUBJECT> (e.g. HANDLER_TIMEOUT_MS_ACCOUNTS_MINECRAFT_ADD)
+/// overrides <PREFIX>.
+fn subject_setting(prefix: &str, subject: &str) -> Option<u64> {
+    let subject_var = format!("{}_{}", prefix, subject.to_uppercase().replace('.', "_"));
+    env::var(subject_var)
+        .or_else(|_| env::var(prefix))
+        .ok()
+        .and_then(|v| v.parse().ok())
+}
+
+/// What error replies carry besides the nats service error headers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorBody {
+    /// Nothing, for subjects whose responses have no error fields.
+    Empty,
+    /// A ChangeMinecraftAccountResponse with the error code and message, like the handlers' own errors.
+    Change,
+}
+
+/// How long a subject's handler may run, how many of its requests are handled at once and
+/// what its error replies carry.
+#[derive(Debug, Clone, Copy)]
+pub struct HandlerOptions {
+    pub deadline: Duration,
+    pub max_in_flight: usize,
+    pub error_body: ErrorBody,
+}
+
+impl HandlerOptions {
+
+    /// The deadline from HANDLER_TIMEOUT_MS, or the given default, and the requests in flight from
+    /// HANDLER_MAX_IN_FLIGHT, or 32. Keeps a burst from opening unlimited mojang requests and
+    /// queueing everything else behind the database pool.
+    pub fn from_env(subject: &str, default_deadline: Duration, error_body: ErrorBody) -> Self {
+        HandlerOptions {
+            deadline: subject_setting("HANDLER_TIMEOUT_MS", subject)
+                .map(Duration::from_millis)
+                .unwrap_or(default_deadline),
+            max_in_flight: subject_setting("HANDLER_MAX_IN_FLIGHT", subject)
+                .map(|v| v.max(1) as usize)
+                .unwrap_or(32),
+            error_body,
+        }
+    }
+}
+
+/// Registers the service so it shows up in `nats micro ls/info/stats`, replicas in the same
+/// queue group share the requests.
+pub async fn start_service(nc: &async_nats::Client, name: &str, queue_group: &str) -> Result<Service> {
+    nc.service_builder()
+        .description("Links minecraft accounts to users and keeps the whitelist in sync.")
+        .queue_group(queue_group)
+        .start(name, env!("CARGO_PKG_VERSION"))
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+}
+
+/// A service request that remembers if it was answered, so error and timeout replies
+/// never go out after the handler already replied.
+#[derive(Debug)]
+pub struct Request {
+    inner: service::Request,
+    nc: async_nats::Client,
+    error_body: ErrorBody,
+    responded: AtomicBool,
+    /// From the caller's Correlation-Id header, or generated, it is in every error reply and log.
+    pub correlation_id: String,
+    /// When the handler is stopped and the caller gets a timeout, the subject's deadline
+    /// shortened to the caller's Request-Timeout-Ms.
+    pub deadline: Instant,
+}
+
+impl Request {
+
+    pub async fn respond(&self, payload: Bytes) -> Result<()> {
+        self.send(Ok(payload)).await
+    }
+
+    /// Replies with the nats service error headers, and for change subjects a response with the
+    /// error's code and message. Only replies without a body count towards the endpoint's errors,
+    /// the nats service api can't send one with them.
+    pub async fn respond_error(&self, error: &AccountError) -> Result<()> {
+        let status = format!("{} (correlation id {})", error, self.correlation_id);
+        if self.error_body == ErrorBody::Empty {
+            return self.send(Err(service::error::Error { status, code: error.status() })).await;
+        }
+
+        let reply = match &self.inner.message.reply {
+            Some(reply) if !self.responded.swap(true, Ordering::SeqCst) => reply.clone(),
+            _ => return Ok(()),
+        };
+        let mut resp = ChangeMinecraftAccountResponse::new();
+        resp.success = false;
+        resp.error_code = error.code().into();
+        resp.error_message = Some(status.clone());
+        let mut headers = async_nats::HeaderMap::new();
+        headers.insert(service::NATS_SERVICE_ERROR, status.as_str());
+        headers.insert(service::NATS_SERVICE_ERROR_CODE, error.status().to_string().as_str());
+        self.nc.publish_with_headers(reply, headers, resp.write_to_bytes()?.into()).await?;
+        Ok(())
+    }
+
+    /// Whether the caller already got its reply.
+    pub fn responded(&self) -> bool {
+        self.responded.load(Ordering::SeqCst)
+    }
+
+    async fn send(&self, response: std::result::Result<Bytes, service::error::Error>) -> Result<()> {
+        if self.inner.message.reply.is_none() || self.responded.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.inner.respond(response).await?;
+        Ok(())
+    }
+}
+
+impl Deref for Request {
+    type Target = async_nats::Message;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner.message
+    }
+}
+
+/// Handles each request to the endpoint in its own task, replying with a timeout error if the
+/// handler runs past its deadline, or a bad request or internal error if it fails before replying. Replies
+/// busy when the subject already has its max requests in flight.
+/// On shutdown it unsubscribes, then waits up to the grace period for requests in flight.
+pub async fn handle_requests<F, Fut>(nc: async_nats::Client, service: &Service, name: &str, subject: &str, default_deadline: Duration, shutdown: watch::Receiver<bool>, f: F) -> Result<()>
+where
+    F: Fn(async_nats::Client, Arc<Request>) -> Fut + Send + Clone /* works better than copy*/ + Sync + 'static,
+    Fut:  Future<Output = Result<()>> + Send + 'static,
+{
+    let options = HandlerOptions::from_env(subject, default_deadline, ErrorBody::Empty);
+    handle_requests_with_options(nc, service, name, subject, options, shutdown, f).await
+}
+
+/// [`handle_requests`] for subjects that reply with a ChangeMinecraftAccountResponse, its error
+/// replies carry one with the code and message too.
+pub async fn handle_change_requests<F, Fut>(nc: async_nats::Client, service: &Service, name: &str, subject: &str, default_deadline: Duration, shutdown: watch::Receiver<bool>, f: F) -> Result<()>
 where
-    F: Fn(async_nats::Client, async_nats::Message) -> Fut + Send + Clone /* works better than copy*/ + Sync + 'static,
+    F: Fn(async_nats::Client, Arc<Request>) -> Fut + Send + Clone /* works better than copy*/ + Sync + 'static,
+    Fut:  Future<Output = Result<()>> + Send + 'static,
+{
+    let options = HandlerOptions::from_env(subject, default_deadline, ErrorBody::Change);
+    handle_requests_with_options(nc, service, name, subject, options, shutdown, f).await
+}
+
+/// [`handle_requests`] with the options given instead of read from the environment.
+pub async fn handle_requests_with_options<F, Fut>(nc: async_nats::Client, service: &Service, name: &str, subject: &str, options: HandlerOptions, mut shutdown: watch::Receiver<bool>, f: F) -> Result<()>
+where
+    F: Fn(async_nats::Client, Arc<Request>) -> Fut + Send + Clone /* works better than copy*/ + Sync + 'static,
     Fut:  Future<Output = Result<()>> + Send + 'static,
 {
     let subject = subject.to_string();
+    let deadline = options.deadline;
+    let timeouts = Arc::new(AtomicU64::new(0));
+    let permits = Arc::new(Semaphore::new(options.max_in_flight));
+    let mut rejected: u64 = 0;
+
+    let mut endpoint = service.endpoint_builder()
+        .name(name)
+        .add(subject.clone())
+        .await
+        .map_err(|e| anyhow::anyhow!(e))?;
+
+    let mut tasks = JoinSet::new();
+    let mut stopping = false;
+
+    loop {
+        let request = tokio::select! {
+            request = endpoint.next() => match request {
+                Some(request) => request,
+                None => break,
+            },
+            _ = shutdown.changed(), if !stopping => {
+                // Requests that already arrived still come through before the stream ends.
+                stopping = true;
+                endpoint.stop().await?;
+                continue;
+            },
+            Some(_) = tasks.join_next() => continue,
+        };
+
+        let correlation_id = request.message.headers.as_ref()
+            .and_then(|h| h.get(CORRELATION_ID_HEADER))
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+
+        // Don't keep working on requests the client will have given up on.
+        let client_deadline = request.message.headers.as_ref()
+            .and_then(|h| h.get(REQUEST_TIMEOUT_HEADER))
+            .and_then(|v| v.as_str().parse::<u64>().ok())
+            .map(Duration::from_millis);
+        let timeout = client_deadline.map_or(deadline, |d| d.min(deadline));
 
-    let mut subscription = nc.subscribe(subject).await?;
+        let request = Arc::new(Request {
+            inner: request,
+            nc: nc.clone(),
+            error_body: options.error_body,
+            responded: AtomicBool::new(false),
+            correlation_id,
+            deadline: Instant::now() + timeout,
+        });
 
-    while let Some(msg) = subscription.next().await {
+        // Reject right away when saturated, a retry will likely land on another replica.
+        let permit = match permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                rejected += 1;
+                warn!("{} is busy, {} requests rejected so far", subject, rejected);
+                if let Err(e) = request.respond_error(&AccountError::Busy).await {
+                    error!("Error sending busy reply: {}", e.to_string());
+                }
+                continue;
+            },
+        };
 
         let nc = nc.clone();
         let f = f.clone();
+        let subject = subject.clone();
+        let timeouts = timeouts.clone();
 
-        // Normally this is 300 ms, but bumping to 1000 due to the mojang account lookup
-        task::spawn(tokio::time::timeout(Duration::from_millis(1000), async move {
-            let msg = msg;
-            if let Err(e) = f(nc, msg.clone()).await {
-                error!("Error: {}", e.to_string());
-            };
-        }));
+        tasks.spawn(async move {
+            let _permit = permit;
+            match tokio::time::timeout_at(request.deadline, f(nc, request.clone())).await {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => {
+                    let error = AccountError::from_handler_error(&e);
+                    match error {
+                        AccountError::Internal(_) => error!("{} failed [{}]: {:?}", subject, request.correlation_id, e),
+                        _ => warn!("{} rejected [{}]: {}", subject, request.correlation_id, e),
+                    }
+                    if let Err(e) = request.respond_error(&error).await {
+                        error!("Error sending error reply: {}", e.to_string());
+                    }
+                },
+                // The caller got its reply, only the work after it was cut short.
+                Err(_) if request.responded() => {
+                    warn!("{} stopped at its deadline after replying [{}]", subject, request.correlation_id);
+                },
+                Err(_) => {
+                    let count = timeouts.fetch_add(1, Ordering::Relaxed) + 1;
+                    warn!("{} timed out after {:?} [{}], {} timeouts so far", subject, timeout, request.correlation_id, count);
+                    if let Err(e) = request.respond_error(&AccountError::TimedOut).await {
+                        error!("Error sending timeout reply: {}", e.to_string());
+                    }
+                },
+            }
+        });
     }
 
+    if tokio::time::timeout(shutdown_grace(), tasks.join_all()).await.is_err() {
+        warn!("{} still had requests in flight after the grace period", subject);
+    }
+
+    Ok(())
+}
+
+/// How long to wait for requests in flight on shutdown, SHUTDOWN_GRACE_SECS or 20 seconds.
+fn shutdown_grace() -> Duration {
+    env::var("SHUTDOWN_GRACE_SECS").ok()
+        .and_then(|v| v.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::from_secs(20))
+}
+
+/// Resolves on SIGTERM or ctrl-c.
+pub async fn shutdown_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => {},
+        result = tokio::signal::ctrl_c() => result?,
+    }
     Ok(())
 }
 
+/// NATS_QUEUE_GROUP if set, otherwise the app name.
+pub fn get_queue_group(app_name: &str) -> String {
+    env::var("NATS_QUEUE_GROUP").unwrap_or(app_name.to_string())
+}
+
 pub fn get_app_name() -> Option<String> {
     env::current_exe()
         .ok()
@@ -62,6 +341,47 @@ pub fn get_app_name() -> Option<String> {
 }
 
 pub async fn connect_to_database() -> Result<Pool<Postgres>> {
+    connect_and_migrate(&[]).await
+}
+
+/// Connects and runs every migration but the one that requires user ids, for the backfill
+/// that has to run before that one can be applied.
+pub async fn connect_to_database_for_backfill() -> Result<Pool<Postgres>> {
+    connect_and_migrate(&[ENFORCE_USER_ID_MIGRATION]).await
+}
+
+async fn connect_and_migrate(skip: &[i64]) -> Result<Pool<Postgres>> {
+    let pool = open_database().await?;
+
+    // The first_name column is only dropped once the service stopped reading it.
+    let mut skip = skip.to_vec();
+    if read_first_name() {
+        skip.push(DROP_FIRST_NAME_MIGRATION);
+    }
+
+    let mut migrator = sqlx::migrate!();
+    if !skip.is_empty() {
+        migrator.migrations = migrator.migrations.iter()
+            .filter(|m| !skip.contains(&m.version))
+            .cloned()
+            .collect::<Vec<_>>()
+            .into();
+        migrator.set_ignore_missing(true);
+    }
+    migrator
+        .run(&pool)
+        .await?;
+
+    Ok(pool)
+}
+
+/// Whether the deprecated first_name column is still read, READ_FIRST_NAME=false stops it and
+/// lets the migration that drops the column run.
+pub fn read_first_name() -> bool {
+    env::var("READ_FIRST_NAME").map(|v| v != "false").unwrap_or(true)
+}
+
+async fn open_database() -> Result<Pool<Postgres>> {
     // Get Nats Env Variable
     let db_url = match env::var("DATABASE_URL") {
         Ok(value) => value,
@@ -74,10 +394,6 @@ pub async fn connect_to_database() -> Result<Pool<Postgres>> {
         .max_connections(5)
         .connect(&db_url).await?;
 
-    sqlx::migrate!()
-        .run(&pool)
-        .await?;
-
     Ok(pool)
 }
 
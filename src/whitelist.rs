@@ -0,0 +1,178 @@
+use std::collections::HashSet;
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use anyhow::Result;
+use async_nats::Client;
+use futures::StreamExt;
+use protobuf::Message;
+use sqlx::types::Uuid;
+use tokio::time::Instant;
+use crate::proto::whitelist::{ListWhitelistRequest, ListWhitelistResponse, UnwhitelistAccount, WhitelistAccount, WhitelistResponse};
+use crate::store::{AccountStore, OutboxEntry};
+
+const DEFAULT_TIMEOUT_MS: u64 = 250;
+const MAX_BACKOFF_SECS: u64 = 5 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WhitelistAction {
+    Add,
+    Remove,
+}
+
+impl fmt::Display for WhitelistAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WhitelistAction::Add => write!(f, "add"),
+            WhitelistAction::Remove => write!(f, "remove"),
+        }
+    }
+}
+
+impl FromStr for WhitelistAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "add" => Ok(WhitelistAction::Add),
+            "remove" => Ok(WhitelistAction::Remove),
+            _ => Err(anyhow::anyhow!("Unknown whitelist action {}", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WhitelistError {
+    Timeout,
+    Rejected(String),
+    Failed(anyhow::Error),
+}
+
+impl fmt::Display for WhitelistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WhitelistError::Timeout => write!(f, "whitelist request timed out"),
+            WhitelistError::Rejected(message) => write!(f, "whitelist request rejected: {}", message),
+            WhitelistError::Failed(e) => write!(f, "whitelist request failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for WhitelistError {}
+
+pub enum Delivery {
+    Delivered,
+    /// Left for the outbox job, behind an older change for the same account.
+    Queued,
+    Failed(WhitelistError),
+}
+
+fn timeout() -> Duration {
+    static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+    *TIMEOUT.get_or_init(|| Duration::from_millis(
+        env::var("WHITELIST_TIMEOUT_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_TIMEOUT_MS)
+    ))
+}
+
+/// Asks the game servers to whitelist or unwhitelist the account, waiting at most WHITELIST_TIMEOUT_MS.
+pub async fn send(nc: &Client, action: WhitelistAction, minecraft_uuid: &str) -> Result<(), WhitelistError> {
+    let (subject, encoded) = match action {
+        WhitelistAction::Add => {
+            let mut req = WhitelistAccount::new();
+            req.uuid = minecraft_uuid.to_string();
+            ("minecraft.whitelist.add", req.write_to_bytes())
+        },
+        WhitelistAction::Remove => {
+            let mut req = UnwhitelistAccount::new();
+            req.uuid = minecraft_uuid.to_string();
+            ("minecraft.whitelist.remove", req.write_to_bytes())
+        },
+    };
+    let encoded = encoded.map_err(|e| WhitelistError::Failed(e.into()))?;
+
+    let reply = match tokio::time::timeout(timeout(), nc.request(subject, encoded.into())).await {
+        Err(_) => return Err(WhitelistError::Timeout),
+        Ok(Err(e)) => return Err(WhitelistError::Failed(e.into())),
+        Ok(Ok(reply)) => reply,
+    };
+
+    // Older game servers only acknowledge with an empty reply.
+    if reply.payload.is_empty() {
+        return Ok(());
+    }
+
+    let resp = WhitelistResponse::parse_from_bytes(&reply.payload).map_err(|e| WhitelistError::Failed(e.into()))?;
+    if !resp.success {
+        return Err(WhitelistError::Rejected(resp.error_message.unwrap_or("unknown error".to_string())));
+    }
+    Ok(())
+}
+
+/// Marks the change delivered, or schedules a retry with backoff if it failed.
+pub async fn record(db: &Arc<dyn AccountStore>, entry: &OutboxEntry, result: &Result<(), WhitelistError>) -> Result<()> {
+    match result {
+        Ok(()) => db.whitelist_delivered(entry.id).await,
+        Err(e) => {
+            let retry_in = Duration::from_secs((1u64 << entry.attempts.clamp(0, 16)).min(MAX_BACKOFF_SECS));
+            tracing::warn!("Error delivering whitelist {} for {}, retrying in {:?}: {}", entry.action, entry.minecraft_uuid, retry_in, e);
+            db.whitelist_failed(entry.id, &e.to_string(), retry_in).await
+        },
+    }
+}
+
+/// Delivers a change queued by this request right away, so the caller can hear how it went.
+/// Anything that doesn't get delivered is retried by the outbox job.
+pub async fn deliver_now(db: &Arc<dyn AccountStore>, nc: &Client, outbox_id: i64) -> Delivery {
+    let entry = match db.claim_whitelist_entry(outbox_id).await {
+        Ok(Some(entry)) => entry,
+        Ok(None) => {
+            if let Err(e) = db.release_whitelist(outbox_id).await {
+                tracing::error!("Error releasing whitelist change: {:?}", e);
+            }
+            return Delivery::Queued;
+        },
+        Err(e) => {
+            tracing::error!("Error claiming whitelist change: {:?}", e);
+            return Delivery::Queued;
+        },
+    };
+
+    let result = send(nc, entry.action, &entry.minecraft_uuid).await;
+    if let Err(e) = record(db, &entry, &result).await {
+        tracing::error!("Error recording whitelist delivery: {:?}", e);
+    }
+
+    match result {
+        Ok(()) => Delivery::Delivered,
+        Err(e) => Delivery::Failed(e),
+    }
+}
+
+/// Asks every game server for its whitelist, collecting replies until the window closes.
+/// Returns each server's name with its whitelisted uuids in hyphenated form.
+pub async fn list(nc: &Client, window: Duration) -> Result<Vec<(String, HashSet<String>)>> {
+    let inbox = nc.new_inbox();
+    let mut replies = nc.subscribe(inbox.clone()).await?;
+
+    let encoded: Vec<u8> = ListWhitelistRequest::new().write_to_bytes()?;
+    nc.publish_with_reply("minecraft.whitelist.list", inbox, encoded.into()).await?;
+    nc.flush().await?;
+
+    let mut servers = Vec::new();
+    let deadline = Instant::now() + window;
+    while let Ok(Some(msg)) = tokio::time::timeout_at(deadline, replies.next()).await {
+        let resp = ListWhitelistResponse::parse_from_bytes(&msg.payload)?;
+        let mut uuids = HashSet::new();
+        for uuid in resp.uuids {
+            match Uuid::parse_str(&uuid) {
+                Ok(uuid) => { uuids.insert(uuid.to_string()); },
+                Err(_) => tracing::warn!("{} whitelisted an invalid uuid {}", resp.server, uuid),
+            }
+        }
+        servers.push((resp.server, uuids));
+    }
+
+    Ok(servers)
+}